@@ -0,0 +1,256 @@
+use std::{
+    ffi::CString,
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    path::Path,
+};
+
+use log::{info, warn};
+
+use crate::{
+    judger::{self, Runner, Verdict},
+    models::{HelperSpec, InternalError, JudgeResult, JudgeSpec, JudgeStatus, Mount},
+    runner::RunnerIo,
+};
+
+// Exit codes of checkers following testlib conventions.
+// See https://github.com/MikeMirzayanov/testlib/blob/master/testlib.h
+const TESTLIB_OK: i32 = 0;
+const TESTLIB_WRONG_ANSWER: i32 = 1;
+const TESTLIB_PRESENTATION_ERROR: i32 = 2;
+const TESTLIB_DIRT: i32 = 4;
+const TESTLIB_POINTS: i32 = 7;
+const TESTLIB_UNEXPECTED_EOF: i32 = 8;
+/// `quitf(_pc(n), ...)` exits with this code plus `n` (0 to 100), the
+/// percentage of the points of the testcase earned.
+const TESTLIB_PARTIALLY: i32 = 16;
+
+/// Run the checker on runner's output and map its exit code to a verdict.
+/// The checker is called as `checker <input> <output> <answer>` and
+/// whatever it writes to stderr becomes the message of the verdict.
+pub(crate) fn check(
+    checker: &HelperSpec,
+    input_path: Option<&str>,
+    output_path: &str,
+    answer_path: &str,
+) -> Result<Verdict, InternalError> {
+    let input_path = input_path.ok_or(InternalError::CheckerInput)?;
+    let message_path = format!("{}.checker", output_path);
+
+    let args = [input_path, output_path, answer_path]
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, std::ffi::NulError>>()
+        .map_err(InternalError::CheckerArgument)?;
+
+    // Run the checker through the same sandbox as submissions so that
+    // a malicious checker (or a checker fooled by the output) is contained.
+    let mut spec = JudgeSpec::from_c_spec(
+        checker.exe_path.clone(),
+        None,
+        None,
+        None,
+        Some(message_path.clone()),
+        args,
+        vec![],
+        checker.scmp_policy,
+        checker.resource_limit.clone(),
+    );
+    let exe_path = checker.exe_path.to_string_lossy();
+    let paths = [exe_path.as_ref(), input_path, output_path, answer_path];
+    Mount::add_binds(&mut spec.mounts, get_parent_dirs(paths), true);
+    // Only the exit code and stderr of the checker matter, and its stdout
+    // must not reach that of the judger.
+    let stdout = OpenOptions::new().write(true).open("/dev/null")?;
+    let io = RunnerIo {
+        stdin: None,
+        stdout: Some(stdout.into()),
+        stderr: None,
+    };
+
+    let runner = Runner::spawn(&spec, io)?;
+    let outcome = runner.run(spec.resource_limit.real_time)?;
+    let result = judger::get_judge_result(&spec, outcome)?;
+    info!("Checker finished with {:?}.", result.status);
+
+    Ok(get_verdict(result, read_message(&message_path)?))
+}
+
+/// Directories of `paths` to bind into the sandbox of a helper. Those
+/// missing on the host are left out, as binding them would abort the run
+/// rather than let the helper report the missing file.
+pub(crate) fn get_parent_dirs<'p>(
+    paths: impl IntoIterator<Item = &'p str>,
+) -> impl Iterator<Item = &'p Path> {
+    paths
+        .into_iter()
+        .filter_map(|path| Path::new(path).parent())
+        .filter(|dir| dir.is_dir())
+}
+
+/// Derive a verdict from the result of a testlib-compatible helper
+/// (a checker or an interactor) and the message it left.
+pub(crate) fn get_verdict(result: JudgeResult, message: Option<String>) -> Verdict {
+    match (result.status, result.exit_code) {
+        (JudgeStatus::Exited | JudgeStatus::RuntimeError, Some(TESTLIB_POINTS)) => {
+            get_points_verdict(message)
+        }
+        (JudgeStatus::Exited | JudgeStatus::RuntimeError, Some(exit_code))
+            if (TESTLIB_PARTIALLY..=TESTLIB_PARTIALLY + 100).contains(&exit_code) =>
+        {
+            let percent = exit_code - TESTLIB_PARTIALLY;
+            get_score_verdict(Some(f64::from(percent) / 100.0), message)
+        }
+        (JudgeStatus::Exited | JudgeStatus::RuntimeError, Some(exit_code)) => Verdict {
+            status: get_status(exit_code),
+            message,
+            score: None,
+        },
+        (status, _) => {
+            warn!("Helper did not exit normally: {:?}", status);
+            Verdict {
+                status: JudgeStatus::CheckerError,
+                message: result
+                    .message
+                    .or(message)
+                    .or(Some(format!("Helper ended with {:?}.", status))),
+                score: None,
+            }
+        }
+    }
+}
+
+/// Derive a verdict from the points given with `quitp`, which testlib
+/// writes in front of the message as `points <points> <message>`. The
+/// points are the fraction of the points of the testcase earned.
+fn get_points_verdict(message: Option<String>) -> Verdict {
+    let points = message.as_deref().and_then(|message| {
+        let mut tokens = message.split_whitespace().peekable();
+        tokens.next_if_eq(&"points");
+        tokens.next()?.parse::<f64>().ok()
+    });
+    get_score_verdict(points, message)
+}
+
+/// Derive a verdict from the fraction of the points of the testcase earned.
+fn get_score_verdict(points: Option<f64>, message: Option<String>) -> Verdict {
+    match points.filter(|points| points.is_finite()) {
+        Some(points) if points >= 1.0 => Verdict {
+            status: JudgeStatus::Accepted,
+            message,
+            score: None,
+        },
+        Some(points) if points <= 0.0 => Verdict {
+            status: JudgeStatus::WrongAnswer,
+            message,
+            score: None,
+        },
+        Some(points) => Verdict {
+            status: JudgeStatus::PartiallyCorrect,
+            message,
+            score: Some(points),
+        },
+        None => {
+            warn!("Helper gave points without a valid score: {:?}", message);
+            Verdict {
+                status: JudgeStatus::CheckerError,
+                message,
+                score: None,
+            }
+        }
+    }
+}
+
+/// Map checker's exit code to a judge status. Any other exit code, such as
+/// 3 of `_fail`, means the checker itself failed.
+fn get_status(exit_code: i32) -> JudgeStatus {
+    match exit_code {
+        TESTLIB_OK => JudgeStatus::Accepted,
+        TESTLIB_WRONG_ANSWER | TESTLIB_DIRT | TESTLIB_UNEXPECTED_EOF => JudgeStatus::WrongAnswer,
+        TESTLIB_PRESENTATION_ERROR => JudgeStatus::PresentationError,
+        _ => JudgeStatus::CheckerError,
+    }
+}
+
+/// Read and remove the message a helper left.
+pub(crate) fn read_message(message_path: &str) -> Result<Option<String>, InternalError> {
+    let message = match fs::read_to_string(message_path) {
+        Ok(message) => message,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(InternalError::ReadCheckerMessage(e)),
+    };
+    let _ = fs::remove_file(message_path);
+
+    let message = message.trim();
+    Ok((!message.is_empty()).then(|| message.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_testlib_exit_codes() {
+        assert_eq!(get_status(0), JudgeStatus::Accepted);
+        assert_eq!(get_status(1), JudgeStatus::WrongAnswer);
+        assert_eq!(get_status(2), JudgeStatus::PresentationError);
+        assert_eq!(get_status(3), JudgeStatus::CheckerError);
+        assert_eq!(get_status(4), JudgeStatus::WrongAnswer);
+        assert_eq!(get_status(8), JudgeStatus::WrongAnswer);
+    }
+
+    fn exited_with(exit_code: i32) -> JudgeResult {
+        JudgeResult {
+            status: JudgeStatus::RuntimeError,
+            message: None,
+            exit_code: Some(exit_code),
+            signal: None,
+            resource_usage: None,
+            score: None,
+            network: None,
+        }
+    }
+
+    #[test]
+    fn points_give_partial_score() {
+        // Written by `quitp(0.25, "found 1 of 4 edges")`.
+        let message = Some("points 0.25 found 1 of 4 edges".to_string());
+        let verdict = get_verdict(exited_with(TESTLIB_POINTS), message);
+        assert_eq!(verdict.status, JudgeStatus::PartiallyCorrect);
+        assert_eq!(verdict.score, Some(0.25));
+
+        let verdict = get_points_verdict(Some("points 1 ok".to_string()));
+        assert_eq!(verdict.status, JudgeStatus::Accepted);
+
+        let verdict = get_points_verdict(Some("points 0".to_string()));
+        assert_eq!(verdict.status, JudgeStatus::WrongAnswer);
+
+        let verdict = get_points_verdict(Some("points ok".to_string()));
+        assert_eq!(verdict.status, JudgeStatus::CheckerError);
+        assert_eq!(get_points_verdict(None).status, JudgeStatus::CheckerError);
+    }
+
+    #[test]
+    fn partially_gives_percentage() {
+        // Exit code of `quitf(_pc(40), "3 of 5 queries")`.
+        let message = Some("partially correct (40) 3 of 5 queries".to_string());
+        let verdict = get_verdict(exited_with(TESTLIB_PARTIALLY + 40), message);
+        assert_eq!(verdict.status, JudgeStatus::PartiallyCorrect);
+        assert_eq!(verdict.score, Some(0.4));
+
+        let verdict = get_verdict(exited_with(TESTLIB_PARTIALLY), None);
+        assert_eq!(verdict.status, JudgeStatus::WrongAnswer);
+    }
+
+    #[test]
+    fn unknown_exit_code_is_checker_error() {
+        assert_eq!(get_status(42), JudgeStatus::CheckerError);
+        assert_eq!(get_status(-1), JudgeStatus::CheckerError);
+
+        // Beyond `_pc(100)`, e.g. `exit(-1)` or an abort caught by the shell.
+        for exit_code in [255, 134, TESTLIB_PARTIALLY + 101] {
+            let verdict = get_verdict(exited_with(exit_code), None);
+            assert_eq!(verdict.status, JudgeStatus::CheckerError, "{}", exit_code);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::models::CompareMode;
+
+/// Compare runner's output with the expected answer under `mode`.
+pub(crate) fn compare(output_bytes: &[u8], answer_bytes: &[u8], mode: &CompareMode) -> bool {
+    let output_text = String::from_utf8_lossy(output_bytes);
+    let answer_text = String::from_utf8_lossy(answer_bytes);
+    let (output, answer) = (output_text.as_ref(), answer_text.as_ref());
+
+    match mode {
+        CompareMode::Exact => output_bytes == answer_bytes,
+        CompareMode::Lines => get_clean_lines(output) == get_clean_lines(answer),
+        CompareMode::Tokens => output.split_whitespace().eq(answer.split_whitespace()),
+        CompareMode::CaseInsensitive => {
+            get_clean_lines(output).to_lowercase() == get_clean_lines(answer).to_lowercase()
+        }
+        CompareMode::UnorderedLines => get_sorted_lines(output) == get_sorted_lines(answer),
+        CompareMode::Float { abs_eps, rel_eps } => {
+            let mut output_tokens = output.split_whitespace();
+            let mut answer_tokens = answer.split_whitespace();
+            loop {
+                match (output_tokens.next(), answer_tokens.next()) {
+                    (Some(out), Some(ans)) if is_close(out, ans, *abs_eps, *rel_eps) => continue,
+                    (None, None) => return true,
+                    _ => return false,
+                }
+            }
+        }
+    }
+}
+
+/// Trim trailing whitespace of each line and at the end of the content.
+fn get_clean_lines(content: &str) -> String {
+    content
+        .trim_end()
+        .lines()
+        .map(|line| line.trim_end())
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+fn get_sorted_lines(content: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = content
+        .trim_end()
+        .lines()
+        .map(|line| line.trim_end())
+        .collect();
+    lines.sort_unstable();
+    lines
+}
+
+/// Compare two tokens as floating-point numbers if both are numbers,
+/// otherwise as strings.
+fn is_close(output: &str, answer: &str, abs_eps: f64, rel_eps: f64) -> bool {
+    match (output.parse::<f64>(), answer.parse::<f64>()) {
+        (Ok(out), Ok(ans)) if out.is_finite() && ans.is_finite() => {
+            let diff = (out - ans).abs();
+            diff <= abs_eps || diff <= rel_eps * ans.abs()
+        }
+        _ => output == answer,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(output: &str, answer: &str, mode: CompareMode) -> bool {
+        compare(output.as_bytes(), answer.as_bytes(), &mode)
+    }
+
+    #[test]
+    fn lines_ignore_trailing_whitespace() {
+        assert!(check("1 2 \n3\n\n", "1 2\n3", CompareMode::Lines));
+        assert!(!check("1  2\n3", "1 2\n3", CompareMode::Lines));
+    }
+
+    #[test]
+    fn exact_compares_bytes() {
+        assert!(check("1 2\n", "1 2\n", CompareMode::Exact));
+        assert!(!check("1 2", "1 2\n", CompareMode::Exact));
+    }
+
+    #[test]
+    fn tokens_ignore_all_whitespace() {
+        assert!(check("1\n2   3\n", "1 2 3", CompareMode::Tokens));
+        assert!(!check("1 23", "1 2 3", CompareMode::Tokens));
+    }
+
+    #[test]
+    fn case_insensitive_ignores_case() {
+        assert!(check("YES\n", "yes", CompareMode::CaseInsensitive));
+        assert!(!check("YES", "no", CompareMode::CaseInsensitive));
+    }
+
+    #[test]
+    fn unordered_lines_ignore_order() {
+        assert!(check("b\na\nc\n", "a\nb\nc", CompareMode::UnorderedLines));
+        assert!(!check("a\na\nc", "a\nb\nc", CompareMode::UnorderedLines));
+    }
+
+    #[test]
+    fn float_uses_epsilon() {
+        let mode = CompareMode::Float {
+            abs_eps: 1e-6,
+            rel_eps: 1e-6,
+        };
+        assert!(check("0.3333333 x\n", "0.33333333 x", mode));
+        assert!(check("1000000.5", "1000000", mode));
+        assert!(!check("0.334", "0.333", mode));
+        assert!(!check("0.3 y", "0.3 x", mode));
+        assert!(!check("0.3", "0.3 0.3", mode));
+    }
+}
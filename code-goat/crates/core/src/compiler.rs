@@ -0,0 +1,225 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read},
+    path::Path,
+};
+
+use log::info;
+
+use crate::{
+    judger::{self, Runner},
+    models::{
+        CompileResult, CompileSpec, InternalError, JudgeResult, JudgeSpec, JudgeStatus, Mount,
+    },
+    runner::RunnerIo,
+};
+
+/// Maximum number of bytes to read from the head of the diagnostics.
+const DIAGNOSTICS_SIZE: u64 = 64 * 1024;
+
+/// The entry point for compiling a submission.
+pub fn compile(spec: CompileSpec) -> CompileResult {
+    match try_compile(&spec) {
+        Ok(result) => result,
+        Err(e) => CompileResult::from_error(e.to_string()),
+    }
+}
+
+/// Run the compiler in the same sandbox as submissions, with whatever it
+/// writes to stderr saved as diagnostics.
+fn try_compile(spec: &CompileSpec) -> Result<CompileResult, InternalError> {
+    let profile = &spec.profile;
+    let args = get_args(spec)
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, std::ffi::NulError>>()
+        .map_err(InternalError::CompileArgument)?;
+    let envs = get_envs(spec)
+        .into_iter()
+        .map(CString::new)
+        .collect::<Result<Vec<CString>, std::ffi::NulError>>()
+        .map_err(InternalError::CompileArgument)?;
+
+    let mut judge_spec = JudgeSpec::from_c_spec(
+        profile.compiler_path.clone(),
+        None,
+        None,
+        None,
+        Some(spec.diagnostics_path.clone()),
+        args,
+        envs,
+        profile.scmp_policy,
+        profile.resource_limit.clone(),
+    );
+    let exe_dir = Path::new(&spec.exe_path).parent();
+    Mount::add_binds(&mut judge_spec.mounts, exe_dir, false);
+    let compiler_path = profile.compiler_path.to_string_lossy();
+    let read_only_paths = spec
+        .source_paths
+        .iter()
+        .map(String::as_str)
+        .chain([compiler_path.as_ref()]);
+    Mount::add_binds(
+        &mut judge_spec.mounts,
+        read_only_paths.filter_map(|path| Path::new(path).parent()),
+        true,
+    );
+    judge_spec.workdir = exe_dir.map(|dir| dir.to_string_lossy().into_owned());
+
+    // Remove the stale executable so that it is never mistaken for the
+    // output of this compilation.
+    match fs::remove_file(&spec.exe_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    // Compilers report everything worth showing to stderr.
+    let stdout = OpenOptions::new().write(true).open("/dev/null")?;
+    let io = RunnerIo {
+        stdin: None,
+        stdout: Some(stdout.into()),
+        stderr: None,
+    };
+
+    let runner = Runner::spawn(&judge_spec, io)?;
+    let outcome = runner.run(judge_spec.resource_limit.real_time)?;
+    let result = judger::get_judge_result(&judge_spec, outcome)?;
+    info!("Compiler finished with {:?}.", result.status);
+
+    get_compile_result(spec, result)
+}
+
+/// Expand `{sources}` and `{exe}` in the arguments of the profile.
+fn get_args(spec: &CompileSpec) -> Vec<String> {
+    spec.profile
+        .args
+        .iter()
+        .flat_map(|arg| match arg.as_str() {
+            "{sources}" => spec.source_paths.clone(),
+            arg => vec![arg.replace("{exe}", &spec.exe_path)],
+        })
+        .collect()
+}
+
+/// Let compilers keep temporary files next to the executable unless told
+/// otherwise, as `/tmp` is read-only inside the sandbox.
+fn get_envs(spec: &CompileSpec) -> Vec<String> {
+    let mut envs = spec.profile.envs.clone();
+    if !envs.iter().any(|env| env.starts_with("TMPDIR=")) {
+        let exe_dir = Path::new(&spec.exe_path)
+            .parent()
+            .map_or("/".into(), |dir| dir.to_string_lossy());
+        envs.push(format!("TMPDIR={}", exe_dir));
+    }
+    envs
+}
+
+fn get_compile_result(
+    spec: &CompileSpec,
+    result: JudgeResult,
+) -> Result<CompileResult, InternalError> {
+    if result.status == JudgeStatus::InternalError {
+        return Ok(CompileResult {
+            status: result.status,
+            message: result.message,
+            exe_path: None,
+            resource_usage: result.resource_usage,
+        });
+    }
+
+    let diagnostics = read_diagnostics(&spec.diagnostics_path)?;
+    if result.status == JudgeStatus::Exited && Path::new(&spec.exe_path).is_file() {
+        return Ok(CompileResult {
+            status: JudgeStatus::Exited,
+            message: diagnostics,
+            exe_path: Some(spec.exe_path.clone()),
+            resource_usage: result.resource_usage,
+        });
+    }
+
+    let cause = match (&result.status, result.exit_code) {
+        (JudgeStatus::Exited, _) => Some(format!("Compiler did not produce {}.", spec.exe_path)),
+        // Diagnostics tell enough when the compiler rejects the source.
+        (JudgeStatus::RuntimeError, Some(_)) => None,
+        (JudgeStatus::RuntimeError | JudgeStatus::SecurityViolation, None) => result.message,
+        (status, _) => Some(format!("Compilation ended with {:?}.", status)),
+    };
+    let message = match (cause, diagnostics) {
+        (Some(cause), Some(diagnostics)) => Some(format!("{}\n{}", cause, diagnostics)),
+        (cause, diagnostics) => cause.or(diagnostics),
+    };
+
+    Ok(CompileResult {
+        status: JudgeStatus::CompileError,
+        message,
+        exe_path: None,
+        resource_usage: result.resource_usage,
+    })
+}
+
+/// Read the head of the diagnostics, which usually has the first error.
+fn read_diagnostics(diagnostics_path: &str) -> Result<Option<String>, InternalError> {
+    let mut diagnostics = Vec::new();
+    match File::open(diagnostics_path) {
+        Ok(file) => file
+            .take(DIAGNOSTICS_SIZE)
+            .read_to_end(&mut diagnostics)
+            .map_err(InternalError::ReadDiagnostics)?,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(InternalError::ReadDiagnostics(e)),
+    };
+
+    let diagnostics = String::from_utf8_lossy(&diagnostics);
+    let diagnostics = diagnostics.trim();
+    Ok((!diagnostics.is_empty()).then(|| diagnostics.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::{CompileProfile, ResourceLimit},
+        sandbox::seccomp::ScmpPolicy,
+    };
+
+    fn spec(args: &[&str]) -> CompileSpec {
+        CompileSpec {
+            source_paths: vec!["/work/a.c".to_string(), "/work/b.c".to_string()],
+            exe_path: "/work/main".to_string(),
+            diagnostics_path: "/work/main.diag".to_string(),
+            profile: CompileProfile {
+                compiler_path: CString::new("/usr/bin/gcc").unwrap(),
+                args: args.iter().map(|arg| arg.to_string()).collect(),
+                envs: vec![],
+                scmp_policy: ScmpPolicy::Compile,
+                resource_limit: ResourceLimit::new(None, None, None, None, None, None),
+            },
+        }
+    }
+
+    #[test]
+    fn expands_sources_and_exe() {
+        let spec = spec(&["-O2", "-o", "{exe}", "{sources}", "-Wl,-Map={exe}.map"]);
+        assert_eq!(
+            get_args(&spec),
+            [
+                "-O2",
+                "-o",
+                "/work/main",
+                "/work/a.c",
+                "/work/b.c",
+                "-Wl,-Map=/work/main.map"
+            ]
+        );
+    }
+
+    #[test]
+    fn sets_tmpdir_to_exe_dir() {
+        let mut spec = spec(&[]);
+        assert_eq!(get_envs(&spec), ["TMPDIR=/work"]);
+
+        spec.profile.envs = vec!["TMPDIR=/scratch".to_string()];
+        assert_eq!(get_envs(&spec), ["TMPDIR=/scratch"]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{collections::BTreeSet, sync::Arc};
+
+use log::{debug, info};
+use parking_lot::{Condvar, Mutex};
+
+use crate::models::InternalError;
+
+/// Pool the judger leases cores from when a run does not specify its cpus.
+static CPU_POOL: Mutex<Option<CpuPool>> = Mutex::new(None);
+
+/// Hand out cores in `cpus` (e.g. "2-15") to runs, so that each run is
+/// pinned to a core no other run uses at the same time.
+pub fn configure_cpu_pool(cpus: &str) -> Result<(), InternalError> {
+    let pool = CpuPool::new(parse_cpu_list(cpus)?);
+    *CPU_POOL.lock() = Some(pool);
+    info!("Configured cpu pool with cpus {}.", cpus);
+    Ok(())
+}
+
+/// Lease a core from the configured pool, if any.
+pub(crate) fn lease_cpu() -> Option<CpuLease> {
+    Some(get_pool()?.acquire())
+}
+
+/// The configured pool, if any. It is cloned so that waiting for a core
+/// does not block others from reading the configuration.
+pub(crate) fn get_pool() -> Option<CpuPool> {
+    CPU_POOL.lock().clone()
+}
+
+/// A set of cores shared by parallel judges. Cloning a pool shares it.
+#[derive(Clone)]
+pub struct CpuPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    free: Mutex<BTreeSet<usize>>,
+    released: Condvar,
+}
+
+impl CpuPool {
+    pub fn new(cpus: impl IntoIterator<Item = usize>) -> Self {
+        Self {
+            inner: Arc::new(PoolInner {
+                free: Mutex::new(cpus.into_iter().collect()),
+                released: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Lease the lowest free core, waiting until one is released if every
+    /// core is in use.
+    pub fn acquire(&self) -> CpuLease {
+        let mut free = self.inner.free.lock();
+        loop {
+            if let Some(cpu) = free.pop_first() {
+                debug!("Leased cpu {}.", cpu);
+                return CpuLease {
+                    cpu,
+                    pool: self.clone(),
+                };
+            }
+            self.inner.released.wait(&mut free);
+        }
+    }
+
+    /// Lease the lowest free core, if any.
+    pub fn try_acquire(&self) -> Option<CpuLease> {
+        let cpu = self.inner.free.lock().pop_first()?;
+        Some(CpuLease {
+            cpu,
+            pool: self.clone(),
+        })
+    }
+}
+
+/// A core leased from a [`CpuPool`]. The core returns to the pool on drop.
+pub struct CpuLease {
+    cpu: usize,
+    pool: CpuPool,
+}
+
+impl CpuLease {
+    pub fn cpu(&self) -> usize {
+        self.cpu
+    }
+}
+
+impl Drop for CpuLease {
+    fn drop(&mut self) {
+        self.pool.inner.free.lock().insert(self.cpu);
+        self.pool.inner.released.notify_one();
+        debug!("Released cpu {}.", self.cpu);
+    }
+}
+
+/// Parse a cpu list in the format of `cpuset.cpus` (e.g. "0-3,8").
+fn parse_cpu_list(cpus: &str) -> Result<BTreeSet<usize>, InternalError> {
+    let invalid = || InternalError::CpuList(cpus.to_string());
+
+    let mut list = BTreeSet::new();
+    for range in cpus.split(',').map(str::trim) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start: usize = start.trim().parse().map_err(|_| invalid())?;
+        let end: usize = end.trim().parse().map_err(|_| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        list.extend(start..=end);
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ranges_and_singles() {
+        let cpus = parse_cpu_list("0-2, 5,7-7").unwrap();
+        assert_eq!(cpus.into_iter().collect::<Vec<_>>(), [0, 1, 2, 5, 7]);
+
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a").is_err());
+    }
+
+    #[test]
+    fn lease_returns_on_drop() {
+        let pool = CpuPool::new([2, 3]);
+        let first = pool.acquire();
+        let second = pool.acquire();
+        assert_eq!((first.cpu(), second.cpu()), (2, 3));
+        assert!(pool.try_acquire().is_none());
+
+        drop(first);
+        assert_eq!(pool.try_acquire().map(|lease| lease.cpu()), Some(2));
+    }
+}
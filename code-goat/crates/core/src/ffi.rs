@@ -1,13 +1,21 @@
-use std::ffi::{CStr, CString, c_char, c_int, c_uint, c_ulonglong};
+use std::ffi::{CStr, CString, c_char, c_double, c_int, c_uint, c_ulonglong};
 use std::num::TryFromIntError;
+use std::ptr;
+use std::slice;
 use std::str::FromStr;
 
 use nix::libc::c_ushort;
+use serde::Serialize;
 
+use crate::language::LanguageRegistry;
 use crate::logger::LoggerError;
-use crate::models::{JudgeResult, JudgeSpec, JudgeStatus, ResourceLimit, U63};
-use crate::sandbox::seccomp::ScmpPolicy;
-use crate::{judger, logger};
+use crate::models::{
+    Aggregation, BatchMode, CompareMode, CompileProfile, CompileResult, CompileSpec, HelperSpec,
+    JudgeResult, JudgeSpec, Mount, Namespaces, NetworkMode, ProblemReport, ResourceLimit, RootMode,
+    Subtask, Testcase, U63,
+};
+use crate::sandbox::{seccomp::ScmpPolicy, user::IdMap};
+use crate::{compiler, cpu_pool, judger, logger, sandbox, scorer};
 
 #[repr(C)]
 pub struct CJudgeSpec {
@@ -21,6 +29,50 @@ pub struct CJudgeSpec {
     pub envs: *const c_char,
     pub scmp_policy: *const c_char,
     pub resource_limit: CResourceLimit,
+    pub compare_mode: CCompareMode,
+    /// Leave `checker.exe_path` null to compare output with answer.
+    pub checker: CHelperSpec,
+    /// Leave `interactor.exe_path` null to run a non-interactive program.
+    pub interactor: CHelperSpec,
+    /// One of "isolated" (default if null) and "loopback".
+    pub network: *const c_char,
+    /// Set to share the IPC namespace of the host.
+    pub share_ipc: bool,
+    /// Set to share the cgroup namespace of the host.
+    pub share_cgroup: bool,
+    /// One of "masked" (default if null) and "minimal".
+    pub root: *const c_char,
+    pub mounts: *const CMount,
+    pub n_mounts: usize,
+    pub workdir: *const c_char,
+}
+
+/// Leave `source` null to mount a tmpfs of `tmpfs_size` bytes instead of
+/// binding `source`, in which case `read_only` is ignored. A mount with a
+/// null or empty `target` is skipped.
+#[repr(C)]
+pub struct CMount {
+    pub target: *const c_char,
+    pub source: *const c_char,
+    pub read_only: bool,
+    pub tmpfs_size: c_ulonglong,
+}
+
+/// `mode` is one of "lines" (default if null), "exact", "tokens",
+/// "case_insensitive", "unordered_lines" and "float". Epsilons are used
+/// only by "float".
+#[repr(C)]
+pub struct CCompareMode {
+    pub mode: *const c_char,
+    pub abs_eps: c_double,
+    pub rel_eps: c_double,
+}
+
+#[repr(C)]
+pub struct CHelperSpec {
+    pub exe_path: *const c_char,
+    pub scmp_policy: *const c_char,
+    pub resource_limit: CResourceLimit,
 }
 
 #[repr(C)]
@@ -31,6 +83,7 @@ pub struct CResourceLimit {
     pub stack: c_uint,
     pub n_process: c_ushort,
     pub output: c_uint,
+    pub scratch: c_ulonglong,
 }
 
 impl TryFrom<CResourceLimit> for ResourceLimit {
@@ -45,6 +98,8 @@ impl TryFrom<CResourceLimit> for ResourceLimit {
             stack: wrap_number(limit.stack),
             n_process: wrap_number(limit.n_process),
             output: wrap_number(limit.output),
+            cpus: None,
+            scratch: wrap_number(limit.scratch),
         })
     }
 }
@@ -60,27 +115,226 @@ where
     }
 }
 
+/// `aggregation` is one of "all_or_nothing", "min" and "sum".
+#[repr(C)]
+pub struct CSubtask {
+    pub points: c_double,
+    pub aggregation: *const c_char,
+    pub testcases: *const CTestcase,
+    pub n_testcases: usize,
+}
+
+#[repr(C)]
+pub struct CCompileSpec {
+    pub compiler_path: *const c_char,
+    /// Elements in `source_paths`, `args` and `envs` should be separated
+    /// by " ". `args` may contain `{sources}` and `{exe}`.
+    pub source_paths: *const c_char,
+    pub exe_path: *const c_char,
+    pub diagnostics_path: *const c_char,
+    pub args: *const c_char,
+    pub envs: *const c_char,
+    pub scmp_policy: *const c_char,
+    pub resource_limit: CResourceLimit,
+}
+
+#[repr(C)]
+pub struct CTestcase {
+    pub input_path: *const c_char,
+    pub answer_path: *const c_char,
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn judger_judge(spec: CJudgeSpec) -> *mut c_char {
-    let generate_error = |message: String| JudgeResult {
-        status: JudgeStatus::InternalError,
-        message: Some(message),
-        exit_code: None,
-        signal: None,
-        resource_usage: None,
-    };
-
     let result = match parse(spec) {
         Ok(spec) => judger::judge(spec),
-        Err(key) => generate_error(format!("Failed to parse: {}", key)),
+        Err(key) => JudgeResult::from_error(format!("Failed to parse: {}", key)),
+    };
+
+    into_json(&result, "{}")
+}
+
+/// Judge one executable against `n_testcases` testcases and return
+/// a JSON array with a result per judged testcase. If `stop_on_failure`
+/// is set, the array ends at the first testcase that is not passed.
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_judge_batch(
+    spec: CJudgeSpec,
+    testcases: *const CTestcase,
+    n_testcases: usize,
+    stop_on_failure: bool,
+) -> *mut c_char {
+    let mode = if stop_on_failure {
+        BatchMode::StopOnFailure
+    } else {
+        BatchMode::RunAll
     };
 
-    let json = serde_json::to_string_pretty(&result).unwrap_or("{}".to_string());
+    let parsed = parse(spec).and_then(|spec| Ok((spec, parse_testcases(testcases, n_testcases)?)));
+    let results = match parsed {
+        Ok((spec, testcases)) => judger::judge_batch(spec, testcases, mode),
+        Err(key) => vec![JudgeResult::from_error(format!("Failed to parse: {}", key))],
+    };
+
+    into_json(&results, "[]")
+}
+
+/// Judge one executable against `n_subtasks` subtasks and return a JSON
+/// object with the score of the problem and of each subtask.
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_judge_problem(
+    spec: CJudgeSpec,
+    subtasks: *const CSubtask,
+    n_subtasks: usize,
+) -> *mut c_char {
+    let parsed = parse(spec).and_then(|spec| Ok((spec, parse_subtasks(subtasks, n_subtasks)?)));
+    let report = match parsed {
+        Ok((spec, subtasks)) => scorer::judge_problem(spec, subtasks),
+        Err(key) => ProblemReport::from_error(format!("Failed to parse: {}", key)),
+    };
+
+    into_json(&report, "{}")
+}
+
+/// Compile source files in the sandbox and return a JSON object with
+/// the path to the produced executable or the diagnostics.
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_compile(spec: CCompileSpec) -> *mut c_char {
+    let result = match parse_compile_spec(spec) {
+        Ok(spec) => compiler::compile(spec),
+        Err(key) => CompileResult::from_error(format!("Failed to parse: {}", key)),
+    };
+
+    into_json(&result, "{}")
+}
+
+/// Compile `source_paths` (separated by " ") into `workdir` with the
+/// language profile of `language`, and return a JSON object like
+/// [`judger_compile`].
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_compile_language(
+    language: *const c_char,
+    source_paths: *const c_char,
+    workdir: *const c_char,
+    resource_limit: CResourceLimit,
+) -> *mut c_char {
+    let parsed = parse_language_compile(language, source_paths, workdir, resource_limit);
+    let result = match parsed {
+        Ok(spec) => compiler::compile(spec),
+        Err(key) => CompileResult::from_error(format!("Failed to parse: {}", key)),
+    };
+
+    into_json(&result, "{}")
+}
+
+/// Resolve how to run the executable compiled into `workdir` with the
+/// language profile of `language`, and return a JSON object with
+/// `exe_path`, `args`, `scmp_policy` and scaled `resource_limit`.
+/// Returns null if the language is unknown.
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_adjust_to_language(
+    language: *const c_char,
+    workdir: *const c_char,
+    resource_limit: CResourceLimit,
+) -> *mut c_char {
+    let profile = parse_str("language", language)
+        .ok()
+        .and_then(|language| LanguageRegistry::builtin().get(language));
+    let (Some(profile), Ok(workdir), Ok(resource_limit)) = (
+        profile,
+        parse_str("workdir", workdir),
+        ResourceLimit::try_from(resource_limit),
+    ) else {
+        return ptr::null_mut();
+    };
+
+    into_json(&profile.get_run(workdir, &resource_limit), "{}")
+}
+
+/// Serialize `value` into a C string owned by Rust. Callers must release
+/// it with [`judger_free`].
+fn into_json<T: Serialize>(value: &T, fallback: &str) -> *mut c_char {
+    let json = serde_json::to_string_pretty(value).unwrap_or(fallback.to_string());
     CString::new(json)
-        .unwrap_or(CString::new("{}").expect("Cannot fail to unwrap an safe string."))
+        .unwrap_or(CString::new(fallback).expect("Cannot fail to unwrap an safe string."))
         .into_raw()
 }
 
+fn parse_testcases<'a>(
+    testcases: *const CTestcase,
+    n_testcases: usize,
+) -> Result<Vec<Testcase>, &'a str> {
+    if n_testcases == 0 {
+        return Ok(vec![]);
+    }
+    if testcases.is_null() {
+        return Err("testcases");
+    }
+
+    unsafe { slice::from_raw_parts(testcases, n_testcases) }
+        .iter()
+        .map(|testcase| {
+            Ok(Testcase {
+                input_path: parse_optional_str("input_path", testcase.input_path)?,
+                answer_path: parse_optional_str("answer_path", testcase.answer_path)?,
+            })
+        })
+        .collect()
+}
+
+fn parse_subtasks<'a>(
+    subtasks: *const CSubtask,
+    n_subtasks: usize,
+) -> Result<Vec<Subtask>, &'a str> {
+    if n_subtasks == 0 {
+        return Ok(vec![]);
+    }
+    if subtasks.is_null() {
+        return Err("subtasks");
+    }
+
+    unsafe { slice::from_raw_parts(subtasks, n_subtasks) }
+        .iter()
+        .map(|subtask| {
+            let aggregation = match parse_str("aggregation", subtask.aggregation)? {
+                "all_or_nothing" => Aggregation::AllOrNothing,
+                "min" => Aggregation::Min,
+                "sum" => Aggregation::Sum,
+                _ => return Err("aggregation"),
+            };
+            Ok(Subtask {
+                points: subtask.points,
+                aggregation,
+                testcases: parse_testcases(subtask.testcases, subtask.n_testcases)?,
+            })
+        })
+        .collect()
+}
+
+fn parse_mounts<'a>(mounts: *const CMount, n_mounts: usize) -> Result<Vec<Mount>, &'a str> {
+    if n_mounts == 0 {
+        return Ok(vec![]);
+    }
+    if mounts.is_null() {
+        return Err("mounts");
+    }
+
+    unsafe { slice::from_raw_parts(mounts, n_mounts) }
+        .iter()
+        .map(|mount| {
+            let target = match parse_optional_str("mounts", mount.target)? {
+                Some(target) if !target.is_empty() => target,
+                _ => return Ok(None),
+            };
+            Ok(Some(match parse_optional_str("mounts", mount.source)? {
+                Some(source) => Mount::bind(&source, &target, mount.read_only),
+                None => Mount::tmpfs(&target, mount.tmpfs_size),
+            }))
+        })
+        .filter_map(Result::transpose)
+        .collect()
+}
+
 fn parse<'a>(cspec: CJudgeSpec) -> Result<JudgeSpec, &'a str> {
     let exe_path = {
         let source = parse_str("exe_path", cspec.exe_path)?;
@@ -97,8 +351,15 @@ fn parse<'a>(cspec: CJudgeSpec) -> Result<JudgeSpec, &'a str> {
     let scmp_policy = parse_str("scmp_policy", cspec.scmp_policy)
         .and_then(|s| ScmpPolicy::from_str(s).or(Err("scmp_policy")))?;
     let resource_limit = cspec.resource_limit.try_into().or(Err("resource_limit"))?;
+    let compare_mode = parse_compare_mode(cspec.compare_mode)?;
+    let checker = parse_helper("checker", cspec.checker)?;
+    let interactor = parse_helper("interactor", cspec.interactor)?;
+    let network = parse_network(cspec.network)?;
+    let root = parse_root(cspec.root)?;
+    let mounts = parse_mounts(cspec.mounts, cspec.n_mounts)?;
+    let workdir = parse_optional_str("workdir", cspec.workdir)?;
 
-    Ok(JudgeSpec::from_c_spec(
+    let mut spec = JudgeSpec::from_c_spec(
         exe_path,
         input_path,
         answer_path,
@@ -108,7 +369,126 @@ fn parse<'a>(cspec: CJudgeSpec) -> Result<JudgeSpec, &'a str> {
         envs,
         scmp_policy,
         resource_limit,
-    ))
+    );
+    spec.compare_mode = compare_mode;
+    spec.checker = checker;
+    spec.interactor = interactor;
+    spec.network = network;
+    spec.namespaces = Namespaces {
+        ipc: !cspec.share_ipc,
+        cgroup: !cspec.share_cgroup,
+    };
+    spec.root = root;
+    spec.mounts = mounts;
+    spec.workdir = workdir;
+
+    Ok(spec)
+}
+
+fn parse_compile_spec<'a>(cspec: CCompileSpec) -> Result<CompileSpec, &'a str> {
+    let compiler_path = {
+        let source = parse_str("compiler_path", cspec.compiler_path)?;
+        CString::new(source).or(Err("compiler_path"))
+    }?;
+    let source_paths = parse_str_array("source_paths", cspec.source_paths)?;
+    let exe_path = parse_str("exe_path", cspec.exe_path)?.to_string();
+    let diagnostics_path = parse_str("diagnostics_path", cspec.diagnostics_path)?.to_string();
+
+    let args = parse_str_array("args", cspec.args)?;
+    let envs = parse_str_array("envs", cspec.envs)?;
+
+    let scmp_policy = parse_str("scmp_policy", cspec.scmp_policy)
+        .and_then(|s| ScmpPolicy::from_str(s).or(Err("scmp_policy")))?;
+    let resource_limit = cspec.resource_limit.try_into().or(Err("resource_limit"))?;
+
+    Ok(CompileSpec {
+        source_paths,
+        exe_path,
+        diagnostics_path,
+        profile: CompileProfile {
+            compiler_path,
+            args,
+            envs,
+            scmp_policy,
+            resource_limit,
+        },
+    })
+}
+
+fn parse_language_compile<'a>(
+    language: *const c_char,
+    source_paths: *const c_char,
+    workdir: *const c_char,
+    resource_limit: CResourceLimit,
+) -> Result<CompileSpec, &'a str> {
+    let profile = parse_str("language", language)
+        .and_then(|language| LanguageRegistry::builtin().get(language).ok_or("language"))?;
+    let source_paths = parse_str_array("source_paths", source_paths)?;
+    let workdir = parse_str("workdir", workdir)?;
+    let resource_limit = resource_limit.try_into().or(Err("resource_limit"))?;
+
+    profile
+        .get_compile_spec(source_paths, workdir, resource_limit)
+        .or(Err("language"))
+}
+
+fn parse_compare_mode<'a>(cmode: CCompareMode) -> Result<CompareMode, &'a str> {
+    let key = "compare_mode";
+    let Some(mode) = parse_optional_str(key, cmode.mode)? else {
+        return Ok(CompareMode::default());
+    };
+
+    match mode.to_ascii_lowercase().as_str() {
+        "lines" => Ok(CompareMode::Lines),
+        "exact" => Ok(CompareMode::Exact),
+        "tokens" => Ok(CompareMode::Tokens),
+        "case_insensitive" => Ok(CompareMode::CaseInsensitive),
+        "unordered_lines" => Ok(CompareMode::UnorderedLines),
+        "float" => Ok(CompareMode::Float {
+            abs_eps: cmode.abs_eps,
+            rel_eps: cmode.rel_eps,
+        }),
+        _ => Err(key),
+    }
+}
+
+fn parse_network<'a>(network: *const c_char) -> Result<NetworkMode, &'a str> {
+    let key = "network";
+    let Some(network) = parse_optional_str(key, network)? else {
+        return Ok(NetworkMode::default());
+    };
+
+    match network.to_ascii_lowercase().as_str() {
+        "isolated" => Ok(NetworkMode::Isolated),
+        "loopback" => Ok(NetworkMode::Loopback),
+        _ => Err(key),
+    }
+}
+
+fn parse_root<'a>(root: *const c_char) -> Result<RootMode, &'a str> {
+    let key = "root";
+    let Some(root) = parse_optional_str(key, root)? else {
+        return Ok(RootMode::default());
+    };
+
+    match root.to_ascii_lowercase().as_str() {
+        "minimal" => Ok(RootMode::Minimal),
+        "masked" => Ok(RootMode::Masked),
+        _ => Err(key),
+    }
+}
+
+fn parse_helper<'a>(key: &'a str, chelper: CHelperSpec) -> Result<Option<HelperSpec>, &'a str> {
+    let Some(exe_path) = parse_optional_str(key, chelper.exe_path)? else {
+        return Ok(None);
+    };
+    let scmp_policy =
+        parse_str(key, chelper.scmp_policy).and_then(|s| ScmpPolicy::from_str(s).or(Err(key)))?;
+    let resource_limit = chelper.resource_limit.try_into().or(Err(key))?;
+
+    HelperSpec::try_new(&exe_path, scmp_policy, resource_limit)
+        .map(Some)
+        .or(Err(key))
 }
 
 fn parse_str(key: &str, string: *const c_char) -> Result<&str, &str> {
@@ -149,6 +529,15 @@ fn parse_cstr_array(key: &str, array: *const c_char) -> Result<Vec<CString>, &st
     Ok(ok_args.into_iter().map(Result::unwrap_or_default).collect())
 }
 
+fn parse_str_array(key: &str, array: *const c_char) -> Result<Vec<String>, &str> {
+    if array.is_null() {
+        return Ok(vec![]);
+    }
+    let array_str = unsafe { CStr::from_ptr(array) }.to_str().or(Err(key))?;
+
+    Ok(array_str.split_whitespace().map(String::from).collect())
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn judger_free(return_value: *mut c_char) {
     if return_value.is_null() {
@@ -162,7 +551,8 @@ pub extern "C" fn judger_free(return_value: *mut c_char) {
 ////
 /// Examples
 /// ```c
-/// int res = c_grade_output(...);
+/// CCompareMode mode = { "float", 1e-6, 1e-6 };
+/// int res = judger_grade_output(output_path, answer_path, mode);
 /// if (res < 0) { printf("Error Occured"); }
 /// else if (res == 0) { printf("Wrong Answer"); }
 /// else { printf("Accepted"); }
@@ -171,6 +561,7 @@ pub extern "C" fn judger_free(return_value: *mut c_char) {
 pub extern "C" fn judger_grade_output(
     output_path: *const c_char,
     answer_path: *const c_char,
+    compare_mode: CCompareMode,
 ) -> c_int {
     let output_path = match parse_str("output_path", output_path) {
         Ok(path) => path,
@@ -180,13 +571,61 @@ pub extern "C" fn judger_grade_output(
         Ok(path) => path,
         Err(_) => return -1,
     };
+    let compare_mode = match parse_compare_mode(compare_mode) {
+        Ok(mode) => mode,
+        Err(_) => return -1,
+    };
 
-    match judger::is_accepted(output_path, answer_path) {
+    match judger::is_accepted(output_path, answer_path, &compare_mode) {
         Ok(is_accepted) => is_accepted.into(),
         Err(_) => return -1,
     }
 }
 
+/// Set the parent of the cgroups created for each run, e.g. a cgroup
+/// delegated to the worker. Returns 0 on success.
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_configure_cgroup(parent: *const c_char) -> c_int {
+    let result = parse_str("parent", parent).map(sandbox::configure_cgroup_parent);
+    match result {
+        Ok(Ok(_)) => 0,
+        Ok(Err(_)) => 2,
+        Err(_) => 1,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_configure_cpus(cpus: *const c_char) -> c_int {
+    let result = parse_str("cpus", cpus).map(cpu_pool::configure_cpu_pool);
+    match result {
+        Ok(Ok(_)) => 0,
+        Ok(Err(_)) => 2,
+        Err(_) => 1,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn judger_configure_id_map(
+    host_uid: c_uint,
+    host_gid: c_uint,
+    sandbox_uid: c_uint,
+    sandbox_gid: c_uint,
+) -> c_int {
+    // An id of 0 is left to its default, as root is never mapped anyway.
+    let default = IdMap::default();
+    let or_default = |id: c_uint, default_id: u32| if id == 0 { default_id } else { id };
+    let id_map = IdMap {
+        host_uid: or_default(host_uid, default.host_uid),
+        host_gid: or_default(host_gid, default.host_gid),
+        sandbox_uid: or_default(sandbox_uid, default.sandbox_uid),
+        sandbox_gid: or_default(sandbox_gid, default.sandbox_gid),
+    };
+    match sandbox::user::configure_id_map(id_map) {
+        Ok(_) => 0,
+        Err(_) => 2,
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn judger_configure_logger(log_path: *const c_char) -> c_int {
     let path = parse_optional_str("log_path", log_path);
@@ -0,0 +1,259 @@
+use std::{ffi::CString, io, path::Path, thread};
+
+use log::info;
+
+use crate::{
+    checker,
+    cpu_pool::{self, CpuPool},
+    judger::{self, Runner, Verdict},
+    models::{
+        HelperSpec, InternalError, JudgeResult, JudgeSpec, JudgeStatus, Mount, ResourceLimit,
+    },
+    runner::RunnerIo,
+};
+
+/// Real time limit (in milliseconds) to apply when neither real time nor
+/// cpu time is limited, so that a deadlock ends up as a timeout.
+const DEFAULT_REAL_TIME_LIMIT: u32 = 10 * 1000;
+
+/// Run the program along with the interactor, each in its own sandbox,
+/// with stdout of one connected to stdin of the other.
+/// The interactor is called as `interactor <input> <output> [<answer>]`.
+pub(crate) fn interact(
+    spec: &JudgeSpec,
+    interactor: &HelperSpec,
+) -> Result<JudgeResult, InternalError> {
+    interact_on(spec, interactor, cpu_pool::get_pool())
+}
+
+/// Interact like [`interact`], leasing cores from `pool` if any.
+fn interact_on(
+    spec: &JudgeSpec,
+    interactor: &HelperSpec,
+    pool: Option<CpuPool>,
+) -> Result<JudgeResult, InternalError> {
+    let (Some(input_path), Some(output_path)) = (&spec.input_path, &spec.output_path) else {
+        return Err(InternalError::InteractorFiles);
+    };
+    let message_path = format!("{}.interactor", output_path);
+
+    // The output file belongs to the interactor, and only the interactor
+    // decides whether the program is accepted.
+    let mut submission_spec = spec.clone();
+    submission_spec.output_path = None;
+    submission_spec.answer_path = None;
+    submission_spec.checker = None;
+    submission_spec.interactor = None;
+    set_default_real_time(&mut submission_spec.resource_limit);
+
+    let args = [
+        Some(input_path),
+        Some(output_path),
+        spec.answer_path.as_ref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|arg| CString::new(arg.as_str()))
+    .collect::<Result<Vec<CString>, std::ffi::NulError>>()
+    .map_err(InternalError::CheckerArgument)?;
+    let mut interactor_spec = JudgeSpec::from_c_spec(
+        interactor.exe_path.clone(),
+        None,
+        None,
+        None,
+        Some(message_path.clone()),
+        args,
+        vec![],
+        interactor.scmp_policy,
+        interactor.resource_limit.clone(),
+    );
+    set_default_real_time(&mut interactor_spec.resource_limit);
+
+    // Only the directory of the output is writable by the interactor.
+    let output_dir = Path::new(output_path).parent();
+    Mount::add_binds(&mut interactor_spec.mounts, output_dir, false);
+    let exe_path = interactor.exe_path.to_string_lossy();
+    let read_only_paths = [
+        Some(exe_path.as_ref()),
+        Some(input_path.as_str()),
+        spec.answer_path.as_deref(),
+    ];
+    Mount::add_binds(
+        &mut interactor_spec.mounts,
+        checker::get_parent_dirs(read_only_paths.into_iter().flatten()),
+        true,
+    );
+
+    // Cross the standard streams of the program and the interactor.
+    let (to_interactor_rx, to_interactor_tx) = io::pipe()?;
+    let (to_submission_rx, to_submission_tx) = io::pipe()?;
+
+    let submission = Runner::spawn_leasing(
+        &submission_spec,
+        RunnerIo {
+            stdin: Some(to_submission_rx.into()),
+            stdout: Some(to_interactor_tx.into()),
+            stderr: None,
+        },
+        || pool.as_ref().map(CpuPool::acquire),
+    )?;
+    // The program may hold the last free core of the pool, which it does
+    // not return until the interactor ends.
+    let interactor = match Runner::spawn_leasing(
+        &interactor_spec,
+        RunnerIo {
+            stdin: Some(to_interactor_rx.into()),
+            stdout: Some(to_submission_tx.into()),
+            stderr: None,
+        },
+        || pool.as_ref().and_then(CpuPool::try_acquire),
+    ) {
+        Ok(interactor) => interactor,
+        Err(e) => {
+            submission.kill();
+            return Err(e);
+        }
+    };
+
+    // Run both at the same time so that real time of each is measured
+    // independently of the other.
+    let (submission_outcome, interactor_outcome) = thread::scope(|scope| {
+        let handle = scope.spawn(|| interactor.run(interactor_spec.resource_limit.real_time));
+        let submission_outcome = submission.run(submission_spec.resource_limit.real_time);
+        (submission_outcome, handle.join())
+    });
+    let interactor_outcome = interactor_outcome.map_err(|_| InternalError::JoinInteractor)??;
+    info!("Both program and interactor finished.");
+
+    let submission_result = judger::get_judge_result(&submission_spec, submission_outcome?)?;
+    let interactor_result = judger::get_judge_result(&interactor_spec, interactor_outcome)?;
+    let interactor_verdict =
+        checker::get_verdict(interactor_result, checker::read_message(&message_path)?);
+
+    Ok(combine(submission_result, interactor_verdict))
+}
+
+/// Limit real time by default, as the program and the interactor can wait
+/// for each other forever.
+fn set_default_real_time(resource_limit: &mut ResourceLimit) {
+    let default = resource_limit
+        .cpu_time
+        .map_or(DEFAULT_REAL_TIME_LIMIT, |limit| limit.saturating_mul(2));
+    resource_limit.real_time.get_or_insert(default);
+}
+
+/// Combine the results of the program and the interactor.
+/// Limits exceeded by the program come first, then the verdict of the
+/// interactor. A runtime error of the program comes last because it often
+/// crashes only after the interactor has given up on it.
+fn combine(submission: JudgeResult, interactor: Verdict) -> JudgeResult {
+    let is_decided = !matches!(
+        submission.status,
+        JudgeStatus::Exited | JudgeStatus::RuntimeError
+    );
+
+    if is_decided
+        || (submission.status == JudgeStatus::RuntimeError
+            && interactor.status == JudgeStatus::Accepted)
+    {
+        submission
+    } else {
+        JudgeResult {
+            status: interactor.status,
+            message: interactor.message,
+            score: interactor.score,
+            ..submission
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+    use crate::sandbox::seccomp::ScmpPolicy;
+
+    fn submission(status: JudgeStatus) -> JudgeResult {
+        JudgeResult {
+            status,
+            message: None,
+            exit_code: None,
+            signal: None,
+            resource_usage: None,
+            score: None,
+            network: None,
+        }
+    }
+
+    #[test]
+    fn interactor_decides_exited_program() {
+        let result = combine(
+            submission(JudgeStatus::Exited),
+            JudgeStatus::WrongAnswer.into(),
+        );
+        assert_eq!(result.status, JudgeStatus::WrongAnswer);
+    }
+
+    #[test]
+    fn exceeded_limit_comes_first() {
+        let result = combine(
+            submission(JudgeStatus::RealTimeLimitExceeded),
+            JudgeStatus::WrongAnswer.into(),
+        );
+        assert_eq!(result.status, JudgeStatus::RealTimeLimitExceeded);
+    }
+
+    #[test]
+    fn runtime_error_comes_last() {
+        let result = combine(
+            submission(JudgeStatus::RuntimeError),
+            JudgeStatus::WrongAnswer.into(),
+        );
+        assert_eq!(result.status, JudgeStatus::WrongAnswer);
+
+        let result = combine(
+            submission(JudgeStatus::RuntimeError),
+            JudgeStatus::Accepted.into(),
+        );
+        assert_eq!(result.status, JudgeStatus::RuntimeError);
+    }
+
+    #[test]
+    fn interactor_runs_beside_program_on_single_core() {
+        // The program holds the only core of the pool until it ends, which
+        // it does not before the interactor runs.
+        let pool = CpuPool::new([0]);
+        let workspace = env::temp_dir().join(format!("code-goat-interact-{}", process::id()));
+        fs::create_dir_all(&workspace).expect("Failed to create workspace");
+        fs::set_permissions(&workspace, fs::Permissions::from_mode(0o777))
+            .expect("Failed to open up workspace");
+        let input = workspace.join("input");
+        fs::write(&input, "1\n").expect("Failed to write input");
+        let output = workspace.join("output");
+
+        let limit = ResourceLimit::new(None, None, Some(5000), None, None, None);
+        let spec = JudgeSpec::try_new(
+            "/bin/true",
+            input.to_str(),
+            None,
+            output.to_str(),
+            None,
+            vec![],
+            vec![],
+            ScmpPolicy::Unsafe,
+            limit.clone(),
+        )
+        .expect("Invalid spec");
+        let interactor =
+            HelperSpec::try_new("/bin/cp", ScmpPolicy::Unsafe, limit).expect("Invalid spec");
+
+        let result = interact_on(&spec, &interactor, Some(pool.clone()));
+        let _ = fs::remove_dir_all(&workspace);
+
+        let result = result.expect("Failed to interact");
+        assert_eq!(result.status, JudgeStatus::Accepted, "{:?}", result.message);
+        // Both leases are returned.
+        assert_eq!(pool.try_acquire().map(|lease| lease.cpu()), Some(0));
+    }
+}
@@ -1,128 +1,494 @@
 use std::{
-    fs,
-    io::{self, Read, Write},
+    fs::{self, File},
+    io::{self, PipeReader, PipeWriter, Read, Seek, SeekFrom, Write},
+    os::fd::RawFd,
     time::{Duration, Instant},
 };
 
 use log::{error, info};
-use nix::sys::wait::{self, WaitStatus};
+use nix::{
+    sys::{
+        signal::{self, Signal},
+        wait::{self, WaitStatus},
+    },
+    unistd::{self, Pid, SysconfVar},
+};
 
 use crate::{
-    models::{InternalError, JudgeResult, JudgeSpec, JudgeStatus, ResourceUsage},
-    runner,
-    sandbox::{CgroupSandbox, TimeSandbox},
+    checker, comparator,
+    cpu_pool::{self, CpuLease},
+    interactor,
+    models::{
+        BatchMode, CompareMode, InternalError, JudgeResult, JudgeSpec, JudgeStatus, ResourceLimit,
+        ResourceUsage, Testcase,
+    },
+    runner::{self, RunnerChannel, RunnerIo},
+    sandbox::{
+        self, CgroupSandbox, SCRATCH_DIR, TimeSandbox,
+        seccomp::{ScmpMonitor, ScmpPolicy, ScmpViolation},
+        user,
+    },
 };
 
 /// The entry point for judging a submission.
 pub fn judge(spec: JudgeSpec) -> JudgeResult {
-    match try_judge(&spec) {
+    judge_once(&spec)
+}
+
+/// The entry point for judging a submission against many testcases.
+///
+/// The executable, arguments and limits of `spec` are shared by every run;
+/// only `input_path` and `answer_path` are replaced by those of each
+/// testcase. Runs take turns in the cgroup set up for the first one, which
+/// is reset in between so that resource usage of one testcase never leaks
+/// into another.
+pub fn judge_batch(
+    mut spec: JudgeSpec,
+    testcases: Vec<Testcase>,
+    mode: BatchMode,
+) -> Vec<JudgeResult> {
+    let mut results = Vec::with_capacity(testcases.len());
+    let mut cg_sandbox = None;
+
+    for testcase in testcases {
+        spec.input_path = testcase.input_path;
+        spec.answer_path = testcase.answer_path;
+
+        let result = match judge_in_turn(&spec, cg_sandbox.take()) {
+            Ok((result, next)) => {
+                cg_sandbox = next;
+                result
+            }
+            Err(e) => JudgeResult::from_error(e.to_string()),
+        };
+        let is_failed = !result.status.is_passed();
+        results.push(result);
+
+        if is_failed && mode == BatchMode::StopOnFailure {
+            info!("Stopped batch judging at testcase #{}.", results.len());
+            break;
+        }
+    }
+
+    results
+}
+
+/// Judge a run in `cg_sandbox` left by the previous run of the same spec,
+/// or in a new cgroup if there is none. Returns the cgroup as well if the
+/// next run can reuse it.
+fn judge_in_turn(
+    spec: &JudgeSpec,
+    cg_sandbox: Option<CgroupSandbox>,
+) -> Result<(JudgeResult, Option<CgroupSandbox>), InternalError> {
+    if spec.interactor.is_some() {
+        return try_judge(spec).map(|result| (result, None));
+    }
+
+    let runner = Runner::spawn_in(spec, RunnerIo::default(), cpu_pool::lease_cpu, cg_sandbox)?;
+    let (outcome, mut cg_sandbox) = runner.run_in_turn(spec.resource_limit.real_time);
+    let result = get_judge_result(spec, outcome?)?;
+    Ok((result, cg_sandbox.reset().then_some(cg_sandbox)))
+}
+
+pub(crate) fn judge_once(spec: &JudgeSpec) -> JudgeResult {
+    match try_judge(spec) {
         Ok(result) => result,
-        Err(e) => JudgeResult {
-            status: JudgeStatus::InternalError,
-            message: Some(e.to_string()),
-            exit_code: None,
-            signal: None,
-            resource_usage: None,
-        },
+        Err(e) => JudgeResult::from_error(e.to_string()),
     }
 }
 
 /// The main judging logic.
 /// It sets up the sandbox to run the untrusted code, monitors its
 /// execution, and collects resource usage.
-fn try_judge(spec: &JudgeSpec) -> Result<JudgeResult, InternalError> {
-    let cg_sandbox = CgroupSandbox::new(&spec.resource_limit)?;
-    let (setup_rx, mut setup_tx) = io::pipe()?;
-    let (mut abort_rx, abort_tx) = io::pipe()?;
-
-    // Clone a runner process in a new user namespace.
-    let runner_pid = runner::clone(spec, setup_rx, abort_tx)?;
-    info!("Cloned runner process with PID {}", runner_pid);
-
-    // Apply cgroup sandbox to the runner process.
-    cg_sandbox.add_process(runner_pid)?;
-
-    // Prevent the runner process from running longer than specified limit.
-    // Its scope (and thus its drop execution) is intentionally extended
-    // to judger's lifetime so that judger can reap it with [`Drop`].
-    let _timeout_sandbox = spec
-        .resource_limit
-        .real_time
-        .map(|limit| TimeSandbox::new(runner_pid, limit));
-
-    match setup_tx.write(b"1") {
-        Ok(_) => info!("Judger finished setting sandbox; notifying runner to resume..."),
-        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
-            error!("Judger was unable to finish set-up due to runnner's abortion.");
+pub(crate) fn try_judge(spec: &JudgeSpec) -> Result<JudgeResult, InternalError> {
+    if let Some(interactor) = &spec.interactor {
+        return interactor::interact(spec, interactor);
+    }
+
+    let runner = Runner::spawn(spec, RunnerIo::default())?;
+    let outcome = runner.run(spec.resource_limit.real_time)?;
+    get_judge_result(spec, outcome)
+}
+
+/// A runner process cloned into its sandbox. It does not execute the
+/// untrusted code until [`Runner::run`] notifies it to.
+pub(crate) struct Runner {
+    pid: Pid,
+    cg_sandbox: CgroupSandbox,
+    setup_tx: PipeWriter,
+    abort_rx: PipeReader,
+    scmp_monitor: Option<ScmpMonitor>,
+    /// Scratch directory of the runner, held to measure it after exit.
+    scratch: Option<File>,
+    /// Core the runner is pinned to, returned to the pool on drop.
+    _cpu_lease: Option<CpuLease>,
+}
+
+/// How a runner process ended.
+pub(crate) enum Outcome {
+    /// Runner aborted while setting up the sandbox.
+    Aborted { message: String, exit_code: i32 },
+    Exited {
+        exit_code: i32,
+        resource_usage: ResourceUsage,
+    },
+    Signaled {
+        signal: Signal,
+        resource_usage: ResourceUsage,
+    },
+    /// Runner was killed for provoking a syscall against its seccomp policy.
+    Violated {
+        violation: ScmpViolation,
+        resource_usage: ResourceUsage,
+    },
+}
+
+impl Runner {
+    pub(crate) fn spawn(spec: &JudgeSpec, io: RunnerIo) -> Result<Self, InternalError> {
+        Self::spawn_leasing(spec, io, cpu_pool::lease_cpu)
+    }
+
+    /// Spawn a runner pinned to the core `lease_cpu` leases, or unpinned if
+    /// it leases none. Runners that must run alongside another one lease
+    /// without waiting, as the other may hold the last free core.
+    pub(crate) fn spawn_leasing(
+        spec: &JudgeSpec,
+        io: RunnerIo,
+        lease_cpu: impl FnOnce() -> Option<CpuLease>,
+    ) -> Result<Self, InternalError> {
+        Self::spawn_in(spec, io, lease_cpu, None)
+    }
+
+    /// Spawn a runner in `cg_sandbox` reset after a run of the same spec,
+    /// or in a new cgroup if there is none.
+    pub(crate) fn spawn_in(
+        spec: &JudgeSpec,
+        io: RunnerIo,
+        lease_cpu: impl FnOnce() -> Option<CpuLease>,
+        cg_sandbox: Option<CgroupSandbox>,
+    ) -> Result<Self, InternalError> {
+        // Pin the runner to a core of the pool unless the spec picks one.
+        let cpu_lease = match spec.resource_limit.cpus {
+            Some(_) => None,
+            None => lease_cpu(),
+        };
+        let cpus = cpu_lease.as_ref().map(|lease| lease.cpu().to_string());
+        let cg_sandbox = match cg_sandbox {
+            Some(cg_sandbox) if cg_sandbox.pin(cpus.as_deref()) => cg_sandbox,
+            _ => CgroupSandbox::new(&ResourceLimit {
+                cpus: cpus.or_else(|| spec.resource_limit.cpus.clone()),
+                ..spec.resource_limit.clone()
+            })?,
+        };
+        let io = io.open_files(spec)?;
+        let (setup_rx, setup_tx) = io::pipe()?;
+        let (mut notify_rx, notify_tx) = io::pipe()?;
+        let (abort_rx, abort_tx) = io::pipe()?;
+        let (id_map_rx, mut id_map_tx) = io::pipe()?;
+
+        // Clone a runner process in a new user namespace.
+        let channel = RunnerChannel {
+            id_map_rx,
+            setup_rx,
+            notify_tx,
+            abort_tx,
+        };
+        let id_map = user::get_id_map();
+        let pid = runner::clone(spec, io, channel, &cg_sandbox, &id_map)?;
+        info!("Cloned runner process with PID {}", pid);
+
+        // Map the user and group of runner before it sets up the sandbox.
+        if let Err(e) = user::write_id_map(pid, &id_map)
+            .and_then(|_| id_map_tx.write_all(b"1").map_err(InternalError::Notify))
+        {
+            let _ = signal::kill(pid, Signal::SIGKILL);
+            let _ = wait::waitpid(pid, None);
+            return Err(e);
         }
-        Err(e) => return Err(InternalError::Notify(e)),
-    };
 
-    // Capture the start time of runner after set-up.
-    let runner_clock = Instant::now();
-
-    match wait::waitpid(runner_pid, None) {
-        Ok(WaitStatus::Exited(_, exit_code)) => {
-            let runner_duration = runner_clock.elapsed();
-
-            // Check if runner aborted while setting up the sandbox.
-            // If so, respond with an `JudgeStatus::InternalError`.
-            let mut aborted_message = String::new();
-            let _ = abort_rx.read_to_string(&mut aborted_message);
-            if !aborted_message.is_empty() {
-                return Ok(JudgeResult {
-                    status: JudgeStatus::InternalError,
-                    message: Some(aborted_message),
-                    exit_code: Some(exit_code),
-                    signal: None,
-                    resource_usage: None,
-                });
-            };
+        // Take over the seccomp listener of the runner, if it has one.
+        // Runner that aborted before applying seccomp sends nothing. Runner
+        // must not go on without a listener, as its blocked syscalls would
+        // then fail instead of being reported.
+        let scmp_monitor = match read_notify_fd(&mut notify_rx)
+            .map(|notify_fd| ScmpMonitor::new(pid, notify_fd))
+            .transpose()
+        {
+            Ok(scmp_monitor) => scmp_monitor,
+            Err(e) => {
+                let _ = signal::kill(pid, Signal::SIGKILL);
+                let _ = wait::waitpid(pid, None);
+                return Err(InternalError::MonitorSeccomp(e));
+            }
+        };
 
-            if exit_code != 0 {
-                return Ok(JudgeResult {
-                    status: JudgeStatus::RuntimeError,
-                    message: Some("Runner exited with non-zero exit code.".to_string()),
-                    exit_code: Some(exit_code),
-                    signal: None,
-                    resource_usage: None,
-                });
+        // The runner has mounted its scratch directory by now, and its
+        // mount namespace is gone once it exits.
+        let scratch = spec
+            .resource_limit
+            .scratch
+            .and_then(|_| sandbox::open_scratch(pid));
+
+        Ok(Self {
+            pid,
+            cg_sandbox,
+            setup_tx,
+            abort_rx,
+            scmp_monitor,
+            scratch,
+            _cpu_lease: cpu_lease,
+        })
+    }
+
+    /// Kill the runner before it is notified to execute the untrusted code.
+    pub(crate) fn kill(self) {
+        let _ = signal::kill(self.pid, Signal::SIGKILL);
+        let _ = wait::waitpid(self.pid, None);
+    }
+
+    /// Notify the runner to execute the untrusted code and wait until it
+    /// ends. The runner is killed once it runs longer than `real_time_limit`.
+    pub(crate) fn run(mut self, real_time_limit: Option<u32>) -> Result<Outcome, InternalError> {
+        self.wait(real_time_limit)
+    }
+
+    /// Run like [`Runner::run`], handing back the cgroup for the next run.
+    pub(crate) fn run_in_turn(
+        mut self,
+        real_time_limit: Option<u32>,
+    ) -> (Result<Outcome, InternalError>, CgroupSandbox) {
+        let outcome = self.wait(real_time_limit);
+        (outcome, self.cg_sandbox)
+    }
+
+    fn wait(&mut self, real_time_limit: Option<u32>) -> Result<Outcome, InternalError> {
+        // Prevent the runner process from running longer than specified limit.
+        // Its scope (and thus its drop execution) is intentionally extended
+        // to judger's lifetime so that judger can reap it with [`Drop`].
+        let _timeout_sandbox = real_time_limit.map(|limit| TimeSandbox::new(self.pid, limit));
+
+        match self.setup_tx.write(b"1") {
+            Ok(_) => info!("Judger finished setting sandbox; notifying runner to resume..."),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                error!("Judger was unable to finish set-up due to runnner's abortion.");
+            }
+            Err(e) => return Err(InternalError::Notify(e)),
+        };
+
+        // Capture the start time of runner after set-up.
+        let runner_clock = Instant::now();
+
+        let wait_status = wait::waitpid(self.pid, None);
+        let violation = self.scmp_monitor.take().and_then(ScmpMonitor::finish);
+
+        match wait_status {
+            Ok(WaitStatus::Exited(_, exit_code)) => {
+                let runner_duration = runner_clock.elapsed();
+
+                // Check if runner aborted while setting up the sandbox.
+                let mut aborted_message = String::new();
+                let _ = self.abort_rx.read_to_string(&mut aborted_message);
+                if !aborted_message.is_empty() {
+                    error!("Runner aborted: {}", aborted_message);
+                    return Ok(Outcome::Aborted {
+                        message: aborted_message,
+                        exit_code,
+                    });
+                };
+
+                let resource_usage =
+                    get_resource_usage(&self.cg_sandbox, self.scratch.as_ref(), runner_duration)?;
+                Ok(Outcome::Exited {
+                    exit_code,
+                    resource_usage,
+                })
+            }
+            Ok(WaitStatus::Signaled(_, signal, _)) | Ok(WaitStatus::Stopped(_, signal)) => {
+                let runner_duration = runner_clock.elapsed();
+                let resource_usage =
+                    get_resource_usage(&self.cg_sandbox, self.scratch.as_ref(), runner_duration)?;
+                match violation {
+                    Some(violation) => Ok(Outcome::Violated {
+                        violation,
+                        resource_usage,
+                    }),
+                    None => Ok(Outcome::Signaled {
+                        signal,
+                        resource_usage,
+                    }),
+                }
             }
+            Ok(ws) => Err(InternalError::UnsupportedWait(format!("{:?}", ws))),
+            Err(e) => Err(InternalError::Wait(e)),
+        }
+    }
+}
+
+/// Read the fd number of the seccomp listener sent by the runner.
+fn read_notify_fd(notify_rx: &mut PipeReader) -> Option<RawFd> {
+    let mut buf = [0u8; size_of::<RawFd>()];
+    notify_rx.read_exact(&mut buf).ok()?;
 
+    let notify_fd = RawFd::from_ne_bytes(buf);
+    (notify_fd >= 0).then_some(notify_fd)
+}
+
+/// Turn how the runner ended into a judge result, grading its output
+/// if it exited normally.
+pub(crate) fn get_judge_result(
+    spec: &JudgeSpec,
+    outcome: Outcome,
+) -> Result<JudgeResult, InternalError> {
+    match outcome {
+        // Respond with an `JudgeStatus::InternalError` if runner aborted.
+        Outcome::Aborted { message, exit_code } => Ok(JudgeResult {
+            status: JudgeStatus::InternalError,
+            message: Some(message),
+            exit_code: Some(exit_code),
+            signal: None,
+            resource_usage: None,
+            score: None,
+            network: Some(spec.network),
+        }),
+        // Runner may exit on its own after a time limit, a fork, an
+        // allocation or a write fails at the limit, e.g. with `EFBIG`.
+        Outcome::Exited {
+            exit_code,
+            resource_usage,
+        } if exit_code != 0 => {
+            let verdict = get_judge_status(spec, &resource_usage, JudgeStatus::RuntimeError)?;
+            let message = match verdict.status {
+                JudgeStatus::RuntimeError => {
+                    let cause = format!("Runner exited with non-zero exit code {}.", exit_code);
+                    Some(get_error_message(spec, cause))
+                }
+                _ => verdict.message,
+            };
+
+            Ok(JudgeResult {
+                status: verdict.status,
+                message,
+                exit_code: Some(exit_code),
+                signal: None,
+                resource_usage: Some(resource_usage),
+                score: None,
+                network: Some(spec.network),
+            })
+        }
+        Outcome::Exited {
+            exit_code,
+            resource_usage,
+        } => {
             // Parse judge status and resource usage.
-            let resource_usage = get_resource_usage(cg_sandbox, runner_duration)?;
-            let status = get_judge_status(&spec, &resource_usage, JudgeStatus::Exited)?;
+            let verdict = get_judge_status(&spec, &resource_usage, JudgeStatus::Exited)?;
 
             Ok(JudgeResult {
-                status,
-                message: None,
+                status: verdict.status,
+                message: verdict.message,
                 exit_code: Some(exit_code),
                 signal: None,
                 resource_usage: Some(resource_usage),
+                score: verdict.score,
+                network: Some(spec.network),
             })
         }
-        Ok(WaitStatus::Signaled(_, signal, _)) | Ok(WaitStatus::Stopped(_, signal)) => {
-            let runner_duration = runner_clock.elapsed();
-            let resource_usage = get_resource_usage(cg_sandbox, runner_duration)?;
-            let status = get_judge_status(&spec, &resource_usage, JudgeStatus::RuntimeError)?;
+        Outcome::Signaled {
+            signal,
+            resource_usage,
+        } => {
+            // `RLIMIT_FSIZE` sends `SIGXFSZ` to runner that writes too much,
+            // and seccomp without a listener kills runner by `SIGSYS`.
+            let default_status = match signal {
+                Signal::SIGXFSZ => JudgeStatus::OutputLimitExceeded,
+                Signal::SIGSYS if spec.scmp_policy != ScmpPolicy::Unsafe => {
+                    JudgeStatus::SecurityViolation
+                }
+                _ => JudgeStatus::RuntimeError,
+            };
+            let verdict = get_judge_status(&spec, &resource_usage, default_status)?;
+            let message = match verdict.status {
+                JudgeStatus::RuntimeError => {
+                    let cause = format!("Terminated by {}: {}.", signal, describe_signal(signal));
+                    Some(get_error_message(spec, cause))
+                }
+                JudgeStatus::SecurityViolation => {
+                    Some("Killed by seccomp for a blocked system call.".to_string())
+                }
+                _ => verdict.message,
+            };
 
             Ok(JudgeResult {
-                status,
-                // TODO: read from error
-                message: None,
+                status: verdict.status,
+                message,
                 exit_code: None,
                 signal: Some(format!("{:?}", signal)),
                 resource_usage: Some(resource_usage),
+                score: None,
+                network: Some(spec.network),
             })
         }
-        Ok(ws) => Err(InternalError::UnsupportedWait(format!("{:?}", ws))),
-        Err(e) => Err(InternalError::Wait(e)),
+        Outcome::Violated {
+            violation,
+            resource_usage,
+        } => Ok(JudgeResult {
+            status: JudgeStatus::SecurityViolation,
+            message: Some(violation.to_string()),
+            exit_code: None,
+            signal: Some(format!("{:?}", Signal::SIGKILL)),
+            resource_usage: Some(resource_usage),
+            score: None,
+            network: Some(spec.network),
+        }),
+    }
+}
+
+/// Maximum number of bytes to read from the end of stderr of runner.
+const ERROR_TAIL_SIZE: u64 = 4 * 1024;
+
+/// Attach the tail of runner's stderr to the cause of a runtime error,
+/// so that contestants can see e.g. the uncaught exception.
+fn get_error_message(spec: &JudgeSpec, cause: String) -> String {
+    match spec.error_path.as_deref().and_then(read_error_tail) {
+        Some(tail) => format!("{}\n{}", cause, tail),
+        None => cause,
+    }
+}
+
+fn read_error_tail(error_path: &str) -> Option<String> {
+    let mut file = File::open(error_path).ok()?;
+    let size = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(size.saturating_sub(ERROR_TAIL_SIZE)))
+        .ok()?;
+
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).ok()?;
+    let tail = String::from_utf8_lossy(&tail);
+    let tail = tail.trim();
+
+    (!tail.is_empty()).then(|| tail.to_string())
+}
+
+/// Describe the usual cause of runner being terminated by `signal`.
+fn describe_signal(signal: Signal) -> &'static str {
+    match signal {
+        Signal::SIGSEGV => "segmentation fault (invalid memory access)",
+        Signal::SIGBUS => "bus error (invalid memory alignment or access)",
+        Signal::SIGFPE => "floating point exception (e.g. division by zero)",
+        Signal::SIGABRT => "aborted (e.g. failed assertion or uncaught exception)",
+        Signal::SIGILL => "illegal instruction",
+        Signal::SIGSYS => "bad system call",
+        Signal::SIGPIPE => "broken pipe",
+        Signal::SIGKILL => "killed",
+        Signal::SIGXCPU => "cpu time limit exceeded",
+        Signal::SIGXFSZ => "file size limit exceeded",
+        _ => "terminated by signal",
     }
 }
 
 /// Calculate the amount of resources used by runner process.
 fn get_resource_usage(
-    cg_sandbox: CgroupSandbox,
+    cg_sandbox: &CgroupSandbox,
+    scratch: Option<&File>,
     duration: Duration,
 ) -> Result<ResourceUsage, InternalError> {
     let memory = cg_sandbox.read_memory_usage()?;
@@ -134,7 +500,31 @@ fn get_resource_usage(
         .try_into()
         .unwrap_or(u32::MAX);
 
-    Ok(ResourceUsage::new(memory, cpu_time, real_time))
+    Ok(ResourceUsage {
+        n_process: cg_sandbox.read_process_peak(),
+        scratch: scratch.and_then(sandbox::read_scratch_usage),
+        memory_events: cg_sandbox.read_memory_events(),
+        process_failures: cg_sandbox.read_process_failures(),
+        ..ResourceUsage::new(memory, cpu_time, real_time)
+    })
+}
+
+/// The status of a run together with the message explaining it.
+pub(crate) struct Verdict {
+    pub(crate) status: JudgeStatus,
+    pub(crate) message: Option<String>,
+    /// Fraction of the points earned, if partially correct.
+    pub(crate) score: Option<f64>,
+}
+
+impl From<JudgeStatus> for Verdict {
+    fn from(status: JudgeStatus) -> Self {
+        Self {
+            status,
+            message: None,
+            score: None,
+        }
+    }
 }
 
 /// Determine the judge status based on resource usage.
@@ -142,52 +532,443 @@ fn get_judge_status(
     spec: &JudgeSpec,
     resource_usage: &ResourceUsage,
     default_status: JudgeStatus,
-) -> Result<JudgeStatus, InternalError> {
+) -> Result<Verdict, InternalError> {
     if let Some(limit) = spec.resource_limit.cpu_time
         && resource_usage.cpu_time > 0
         && resource_usage.cpu_time > limit
     {
-        Ok(JudgeStatus::CpuTimeLimitExceeded)
+        Ok(JudgeStatus::CpuTimeLimitExceeded.into())
     } else if let Some(limit) = spec.resource_limit.real_time
         && resource_usage.real_time > limit
     {
-        Ok(JudgeStatus::RealTimeLimitExceeded)
-    } else if let Some(limit) = spec.resource_limit.memory
-        && resource_usage.memory > limit.into()
+        Ok(JudgeStatus::RealTimeLimitExceeded.into())
+    } else if default_status != JudgeStatus::Exited
+        // A program killed within the time limits after a fork failed
+        // likely died of the failure, e.g. by a failed assertion.
+        && let Some(verdict) = get_process_limit_verdict(spec, resource_usage)
+    {
+        Ok(verdict)
+    } else if let Some(verdict) =
+        get_memory_limit_verdict(spec, resource_usage, default_status != JudgeStatus::Exited)
+    {
+        Ok(verdict)
+    } else if is_output_limit_exceeded(spec) {
+        Ok(JudgeStatus::OutputLimitExceeded.into())
+    } else if default_status != JudgeStatus::Exited
+        && let Some(verdict) = get_scratch_limit_verdict(spec, resource_usage)
     {
-        Ok(JudgeStatus::MemoryLimitExceeded)
+        Ok(verdict)
     } else if default_status == JudgeStatus::Exited
         && let Some(output) = &spec.output_path
         && let Some(answer) = &spec.answer_path
     {
-        is_accepted(output, answer).map(|accepted| {
-            if accepted {
-                JudgeStatus::Accepted
-            } else {
-                JudgeStatus::WrongAnswer
-            }
-        })
+        match &spec.checker {
+            Some(checker) => checker::check(checker, spec.input_path.as_deref(), output, answer),
+            None => is_accepted(output, answer, &spec.compare_mode).map(|accepted| {
+                if accepted {
+                    JudgeStatus::Accepted.into()
+                } else {
+                    JudgeStatus::WrongAnswer.into()
+                }
+            }),
+        }
     } else {
-        Ok(default_status)
+        Ok(default_status.into())
     }
 }
 
+/// Check if runner failed to create a process at the process limit.
+fn get_process_limit_verdict(spec: &JudgeSpec, resource_usage: &ResourceUsage) -> Option<Verdict> {
+    let limit = spec.resource_limit.n_process?;
+    let failures = resource_usage
+        .process_failures
+        .filter(|&failures| failures > 0)?;
+
+    Some(Verdict {
+        status: JudgeStatus::ProcessLimitExceeded,
+        message: Some(format!(
+            "Failed to create processes {} times beyond the limit of {} (pids.events: max).",
+            failures, limit
+        )),
+        score: None,
+    })
+}
+
+/// Check if runner exceeded the memory limit, explaining which evidence
+/// revealed it. Reclaims at the limit (`max` events) also happen for mere
+/// page cache, so they count only if the run `is_failed`.
+fn get_memory_limit_verdict(
+    spec: &JudgeSpec,
+    resource_usage: &ResourceUsage,
+    is_failed: bool,
+) -> Option<Verdict> {
+    let limit = u64::from(spec.resource_limit.memory?);
+    let events = resource_usage.memory_events.unwrap_or_default();
+
+    let message = if events.oom_kill > 0 {
+        "Killed by the OOM killer at the memory limit (memory.events: oom_kill).".to_string()
+    } else if events.oom > 0 {
+        "Failed to allocate memory at the memory limit (memory.events: oom).".to_string()
+    } else if resource_usage.memory > limit {
+        format!(
+            "Peak memory usage of {} bytes exceeded the limit of {} bytes (peak usage).",
+            resource_usage.memory, limit
+        )
+    } else if is_failed && events.max > 0 {
+        "Failed after reaching the memory limit (memory.events: max).".to_string()
+    } else {
+        return None;
+    };
+
+    Some(Verdict {
+        status: JudgeStatus::MemoryLimitExceeded,
+        message: Some(message),
+        score: None,
+    })
+}
+
+/// Check if runner filled up its scratch directory. The tmpfs is sized in
+/// whole pages, so it is full once the usage reaches the limit rounded up
+/// to a page. Filling it up is only to blame if the run failed.
+fn get_scratch_limit_verdict(spec: &JudgeSpec, resource_usage: &ResourceUsage) -> Option<Verdict> {
+    let limit = spec.resource_limit.scratch?;
+    let usage = resource_usage.scratch?;
+    let page_size = unistd::sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .map_or(4096, |size| size as u64);
+
+    (usage >= limit.next_multiple_of(page_size)).then(|| Verdict {
+        status: JudgeStatus::ScratchLimitExceeded,
+        message: Some(format!(
+            "Filled up the scratch directory {} of {} bytes.",
+            SCRATCH_DIR, limit
+        )),
+        score: None,
+    })
+}
+
+/// Check if runner wrote more than the output limit. `RLIMIT_FSIZE` has
+/// a margin over the limit, so the size of output file is checked as well.
+fn is_output_limit_exceeded(spec: &JudgeSpec) -> bool {
+    let (Some(limit), Some(output_path)) = (spec.resource_limit.output, &spec.output_path) else {
+        return false;
+    };
+
+    fs::metadata(output_path).is_ok_and(|metadata| metadata.len() > limit.into())
+}
+
 /// Check runner's output and expected output to retrieve status.
-pub fn is_accepted(output_path: &str, answer_path: &str) -> Result<bool, InternalError> {
-    let output_content = get_clean_content(output_path)?;
-    let answer_content = get_clean_content(answer_path)?;
+pub fn is_accepted(
+    output_path: &str,
+    answer_path: &str,
+    compare_mode: &CompareMode,
+) -> Result<bool, InternalError> {
+    let output_content = fs::read(output_path).map_err(InternalError::ReadOutput)?;
+    let answer_content = fs::read(answer_path).map_err(InternalError::ReadOutput)?;
 
-    Ok(output_content == answer_content)
+    Ok(comparator::compare(
+        &output_content,
+        &answer_content,
+        compare_mode,
+    ))
 }
 
-fn get_clean_content(path: &str) -> Result<String, InternalError> {
-    let content = fs::read_to_string(path).map_err(InternalError::ReadOutput)?;
-    let clean_content = content
-        .trim_end()
-        .lines()
-        .map(|line| line.trim_end())
-        .collect::<Vec<&str>>()
-        .join("\n");
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        os::unix::fs::{MetadataExt, PermissionsExt},
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use super::*;
+    use crate::{
+        models::Mount,
+        sandbox::user::{IdMap, SANDBOX_ID},
+    };
+
+    /// Directory writable by the sandbox whatever user it is mapped to.
+    fn get_workspace(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("code-goat-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create workspace");
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777))
+            .expect("Failed to open up workspace");
+        dir
+    }
+
+    /// Run `script` by the shell with `workspace` bound writable.
+    fn get_shell_spec(script: &str, workspace: &Path) -> JudgeSpec {
+        let mut spec = JudgeSpec::try_new(
+            "/bin/sh",
+            None,
+            None,
+            None,
+            None,
+            vec!["/bin/sh", "-c", script],
+            vec!["PATH=/usr/bin:/bin"],
+            ScmpPolicy::Unsafe,
+            ResourceLimit::new(None, None, Some(5000), None, None, None),
+        )
+        .expect("Invalid spec");
+        Mount::add_binds(&mut spec.mounts, [workspace], false);
+        spec
+    }
+
+    #[test]
+    fn created_files_are_owned_by_mapped_user() {
+        let workspace = get_workspace("owner");
+        let path = workspace.join("id");
+        let spec = get_shell_spec(&format!("id -u > {}", path.display()), &workspace);
+
+        let result = judge(spec);
+        let owner = fs::metadata(&path).map(|metadata| metadata.uid());
+        let sandbox_uid = fs::read_to_string(&path);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(result.status, JudgeStatus::Exited, "{:?}", result.message);
+        assert_eq!(owner.ok(), Some(IdMap::default().host_uid));
+        assert_eq!(sandbox_uid.ok(), Some(format!("{}\n", SANDBOX_ID)));
+    }
+
+    #[test]
+    fn runtime_error_keeps_resource_usage() {
+        let workspace = get_workspace("crash");
+        let result = judge(get_shell_spec("exit 3", &workspace));
+        let _ = fs::remove_dir_all(&workspace);
 
-    Ok(clean_content)
+        assert_eq!(result.status, JudgeStatus::RuntimeError);
+        assert_eq!(result.exit_code, Some(3));
+        assert!(result.resource_usage.is_some());
+    }
+
+    #[test]
+    fn failed_exit_is_judged_like_a_clean_one() {
+        let workspace = get_workspace("failed-exit");
+        let output_path = workspace.join("output");
+        fs::write(&output_path, [b'x'; 16]).expect("Failed to write output");
+        let mut spec = get_shell_spec("exit 1", &workspace);
+        spec.output_path = Some(output_path.display().to_string());
+        spec.resource_limit.output = Some(8);
+        let judge_exit = |real_time| {
+            let outcome = Outcome::Exited {
+                exit_code: 1,
+                resource_usage: ResourceUsage::new(0, 0, real_time),
+            };
+            get_judge_result(&spec, outcome).expect("Failed to get result")
+        };
+
+        let late = judge_exit(6000);
+        let in_time = judge_exit(10);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(late.status, JudgeStatus::RealTimeLimitExceeded);
+        assert_eq!(in_time.status, JudgeStatus::OutputLimitExceeded);
+        assert!(late.resource_usage.is_some() && in_time.resource_usage.is_some());
+    }
+
+    #[test]
+    fn filling_scratch_is_scratch_limit_exceeded() {
+        const SCRATCH: u64 = 64 * 1024;
+        let workspace = get_workspace("scratch");
+        let script = format!("head -c {} /dev/zero > {}/fill", SCRATCH + 1, SCRATCH_DIR);
+        let mut spec = get_shell_spec(&script, &workspace);
+        spec.resource_limit.scratch = Some(SCRATCH);
+
+        let result = judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(
+            result.status,
+            JudgeStatus::ScratchLimitExceeded,
+            "{:?}",
+            result.message
+        );
+        let usage = result.resource_usage.and_then(|usage| usage.scratch);
+        assert_eq!(usage, Some(SCRATCH));
+    }
+
+    #[test]
+    fn blocked_syscall_is_security_violation() {
+        let workspace = get_workspace("seccomp");
+        let mut spec = get_shell_spec("id -u", &workspace);
+        spec.scmp_policy = ScmpPolicy::Strict;
+
+        let result = judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(
+            result.status,
+            JudgeStatus::SecurityViolation,
+            "{:?}",
+            result.message
+        );
+    }
+
+    #[test]
+    fn threaded_policy_allows_threads() {
+        // The test harness itself is a Rust program running each test on
+        // its own thread.
+        let workspace = get_workspace("threads");
+        let exe = workspace.join("harness");
+        fs::copy(env::current_exe().expect("No test harness"), &exe)
+            .expect("Failed to copy test harness");
+        let output = workspace.join("output");
+        let mut spec = JudgeSpec::try_new(
+            exe.to_str().unwrap(),
+            None,
+            None,
+            output.to_str(),
+            None,
+            vec![
+                "harness",
+                "--exact",
+                "models::tests::u63::saturating_add_within_bounds",
+                "--color",
+                "never",
+            ],
+            vec![],
+            ScmpPolicy::Threaded,
+            ResourceLimit::new(None, None, Some(5000), None, None, None),
+        )
+        .expect("Invalid spec");
+        Mount::add_binds(&mut spec.mounts, [workspace.as_path()], false);
+
+        let result = judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(result.status, JudgeStatus::Exited, "{:?}", result.message);
+    }
+
+    #[test]
+    fn threaded_policy_blocks_processes() {
+        let workspace = get_workspace("processes");
+        let mut spec = get_shell_spec("/bin/true; /bin/true", &workspace);
+        spec.scmp_policy = ScmpPolicy::Threaded;
+
+        let result = judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(
+            result.status,
+            JudgeStatus::SecurityViolation,
+            "{:?}",
+            result.message
+        );
+    }
+
+    /// Judge testcases whose input is `<iterations> <exit code>` of a
+    /// busy loop.
+    fn judge_loops(name: &str, inputs: &[&str], mode: BatchMode) -> Vec<JudgeResult> {
+        let script = "read n code; i=0; while [ $i -lt $n ]; do i=$((i+1)); done; exit $code";
+        judge_inputs(
+            name,
+            get_shell_spec(script, &get_workspace(name)),
+            inputs,
+            mode,
+        )
+    }
+
+    /// Judge testcases of `inputs` by `spec` in the workspace `name`.
+    fn judge_inputs(
+        name: &str,
+        spec: JudgeSpec,
+        inputs: &[&str],
+        mode: BatchMode,
+    ) -> Vec<JudgeResult> {
+        let workspace = get_workspace(name);
+        let testcases = inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| {
+                let path = workspace.join(i.to_string());
+                fs::write(&path, input).expect("Failed to write input");
+                Testcase {
+                    input_path: path.to_str().map(String::from),
+                    answer_path: None,
+                }
+            })
+            .collect();
+
+        let results = judge_batch(spec, testcases, mode);
+        let _ = fs::remove_dir_all(&workspace);
+        results
+    }
+
+    #[test]
+    fn batch_stops_on_failure() {
+        let results = judge_loops("stop", &["0 0", "0 3", "0 0"], BatchMode::StopOnFailure);
+        let statuses: Vec<_> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, [JudgeStatus::Exited, JudgeStatus::RuntimeError]);
+    }
+
+    #[test]
+    fn batch_runs_all_in_reset_cgroup() {
+        let results = judge_loops("all", &["100000 0", "0 3", "0 0"], BatchMode::RunAll);
+        let statuses: Vec<_> = results.iter().map(|result| result.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                JudgeStatus::Exited,
+                JudgeStatus::RuntimeError,
+                JudgeStatus::Exited
+            ]
+        );
+
+        // Cpu time of the busy loop is not carried over to the next runs.
+        let cpu_times: Vec<_> = results
+            .iter()
+            .map(|result| result.resource_usage.as_ref().map(|usage| usage.cpu_time))
+            .collect();
+        assert!(cpu_times[2] < cpu_times[0], "{:?}", cpu_times);
+    }
+
+    #[test]
+    fn batch_reports_process_peak_of_each_run() {
+        let workspace = get_workspace("peak");
+        let script = "read n; i=0; while [ $i -lt $n ]; do /bin/true; i=$((i+1)); done";
+        let mut spec = get_shell_spec(script, &workspace);
+        spec.resource_limit.n_process = Some(4);
+
+        let results = judge_inputs("peak", spec, &["0", "2", "0"], BatchMode::RunAll);
+        let peaks: Vec<_> = results
+            .iter()
+            .map(|result| result.resource_usage.as_ref().map(|usage| usage.n_process))
+            .collect();
+        // The forks of the second run are not reported for the third one.
+        assert_eq!(peaks[2], peaks[0], "{:?}", peaks);
+        assert!(peaks[1] >= peaks[0], "{:?}", peaks);
+    }
+
+    #[test]
+    fn failed_fork_is_process_limit_exceeded() {
+        let workspace = get_workspace("fork");
+        let mut spec = get_shell_spec("/bin/true; /bin/true", &workspace);
+        spec.resource_limit.n_process = Some(1);
+
+        let result = judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(
+            result.status,
+            JudgeStatus::ProcessLimitExceeded,
+            "{:?}",
+            result.message
+        );
+    }
+
+    #[test]
+    fn time_limit_comes_before_process_limit() {
+        let mut spec = get_shell_spec("", &env::temp_dir());
+        spec.resource_limit.n_process = Some(1);
+        let resource_usage = ResourceUsage {
+            process_failures: Some(1),
+            ..ResourceUsage::new(0, 0, 6000)
+        };
+
+        let verdict = get_judge_status(&spec, &resource_usage, JudgeStatus::RuntimeError)
+            .expect("Failed to get status");
+        assert_eq!(verdict.status, JudgeStatus::RealTimeLimitExceeded);
+    }
 }
@@ -0,0 +1,374 @@
+use std::{collections::HashMap, ffi::CString, path::Path, sync::LazyLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::{CompileProfile, CompileSpec, JudgeSpec, Mount, ResourceLimit, U63},
+    sandbox::seccomp::ScmpPolicy,
+};
+
+/// Language profiles shipped with the crate.
+static BUILTIN_LANGUAGES: &str = include_str!("../languages.toml");
+
+static BUILTIN_REGISTRY: LazyLock<LanguageRegistry> = LazyLock::new(|| {
+    LanguageRegistry::from_toml(BUILTIN_LANGUAGES).expect("Built-in language profiles are invalid.")
+});
+
+/// A set of language profiles keyed by language name (e.g. "cpp").
+#[derive(Debug)]
+pub struct LanguageRegistry {
+    profiles: HashMap<String, LanguageProfile>,
+}
+
+impl LanguageRegistry {
+    /// The registry of profiles in `languages.toml`.
+    pub fn builtin() -> &'static Self {
+        &BUILTIN_REGISTRY
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, toml::de::Error> {
+        Ok(Self {
+            profiles: toml::from_str(source)?,
+        })
+    }
+
+    pub fn get(&self, language: &str) -> Option<&LanguageProfile> {
+        self.profiles.get(language)
+    }
+}
+
+/// How to compile and run a program written in a language.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageProfile {
+    /// Name of the executable in the working directory.
+    pub exe: String,
+
+    /// Command to compile the sources into the executable.
+    pub compile: Vec<String>,
+
+    /// Environment variables to set for the compiler.
+    #[serde(default)]
+    pub compile_envs: Vec<String>,
+
+    /// Command to run the executable.
+    pub run: Vec<String>,
+
+    /// Seccomp rule set name to run the executable with.
+    pub scmp_policy: ScmpPolicy,
+
+    /// Scaling of the memory limit.
+    #[serde(default)]
+    pub memory: LimitScale,
+
+    /// Scaling of the cpu and real time limits.
+    #[serde(default)]
+    pub time: LimitScale,
+}
+
+/// Scales a limit into `factor * limit + offset`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitScale {
+    pub factor: u32,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+impl Default for LimitScale {
+    fn default() -> Self {
+        Self {
+            factor: 1,
+            offset: 0,
+        }
+    }
+}
+
+/// Executable, arguments, seccomp policy and limits to run a program with,
+/// resolved from a [`LanguageProfile`].
+#[derive(Debug, Serialize)]
+pub struct LanguageRun {
+    pub exe_path: String,
+    pub args: Vec<String>,
+    pub scmp_policy: ScmpPolicy,
+    pub resource_limit: ResourceLimit,
+}
+
+impl LanguageProfile {
+    /// Absolute path to the executable produced in `workdir`.
+    pub fn get_exe_path(&self, workdir: &str) -> String {
+        Path::new(workdir)
+            .join(&self.exe)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Build a spec to compile `source_paths` in `workdir` within
+    /// `resource_limit`. Diagnostics are saved next to the executable.
+    pub fn get_compile_spec(
+        &self,
+        source_paths: Vec<String>,
+        workdir: &str,
+        resource_limit: ResourceLimit,
+    ) -> Result<CompileSpec, std::ffi::NulError> {
+        let exe_path = self.get_exe_path(workdir);
+        let (compiler_path, args) = match self.compile.split_first() {
+            Some((compiler_path, args)) => (compiler_path.as_str(), args),
+            None => ("", &[][..]),
+        };
+
+        Ok(CompileSpec {
+            source_paths,
+            diagnostics_path: format!("{}.log", exe_path),
+            exe_path,
+            profile: CompileProfile {
+                compiler_path: CString::new(expand_workdir(compiler_path, workdir))?,
+                args: args
+                    .iter()
+                    .map(|arg| expand_workdir(arg, workdir))
+                    .collect(),
+                envs: self
+                    .compile_envs
+                    .iter()
+                    .map(|env| expand_workdir(env, workdir))
+                    .collect(),
+                scmp_policy: ScmpPolicy::Compile,
+                resource_limit,
+            },
+        })
+    }
+
+    /// Resolve how to run the executable in `workdir`, with limits scaled
+    /// from `resource_limit`.
+    pub fn get_run(&self, workdir: &str, resource_limit: &ResourceLimit) -> LanguageRun {
+        let exe_path = self.get_exe_path(workdir);
+        let memory_mib = resource_limit
+            .memory
+            .map(|limit| (u64::from(limit) >> 20).max(1).to_string());
+        let mut command = self.run.iter().filter_map(|arg| {
+            let arg = expand_workdir(arg, workdir).replace("{exe}", &exe_path);
+            match &memory_mib {
+                Some(memory_mib) => Some(arg.replace("{memory_mib}", memory_mib)),
+                None => (!arg.contains("{memory_mib}")).then_some(arg),
+            }
+        });
+
+        LanguageRun {
+            exe_path: command.next().unwrap_or_default(),
+            args: command.collect(),
+            scmp_policy: self.scmp_policy,
+            resource_limit: self.scale(resource_limit),
+        }
+    }
+
+    /// Adjust `spec` to run the executable in `workdir`, which is bound
+    /// read-only unless `spec` already mounts it. Arguments already in
+    /// `spec` are passed after those of the run command.
+    pub fn adjust(&self, spec: &mut JudgeSpec, workdir: &str) -> Result<(), std::ffi::NulError> {
+        let run = self.get_run(workdir, &spec.resource_limit);
+        let exe_path = CString::new(run.exe_path)?;
+
+        let user_args = spec.args.iter().skip_while(|arg| **arg == spec.exe_path);
+        let args = run
+            .args
+            .into_iter()
+            .map(CString::new)
+            .collect::<Result<Vec<CString>, std::ffi::NulError>>()?;
+        spec.args = [exe_path.clone()]
+            .into_iter()
+            .chain(args)
+            .chain(user_args.cloned())
+            .collect();
+
+        spec.exe_path = exe_path;
+        spec.scmp_policy = run.scmp_policy;
+        spec.resource_limit = run.resource_limit;
+        Mount::add_binds(&mut spec.mounts, [Path::new(workdir)], true);
+        spec.workdir = Some(workdir.to_string());
+        Ok(())
+    }
+
+    fn scale(&self, resource_limit: &ResourceLimit) -> ResourceLimit {
+        let scale_time = |limit: u32| {
+            let scaled = u64::from(limit) * u64::from(self.time.factor) + self.time.offset;
+            u32::try_from(scaled).unwrap_or(u32::MAX)
+        };
+
+        ResourceLimit {
+            memory: resource_limit.memory.map(|limit| {
+                let scaled = u64::from(limit).saturating_mul(self.memory.factor.into());
+                U63::try_from(scaled)
+                    .unwrap_or(U63::MAX)
+                    .saturating_add(self.memory.offset)
+            }),
+            cpu_time: resource_limit.cpu_time.map(scale_time),
+            real_time: resource_limit.real_time.map(scale_time),
+            ..resource_limit.clone()
+        }
+    }
+}
+
+fn expand_workdir(arg: &str, workdir: &str) -> String {
+    arg.replace("{workdir}", workdir.trim_end_matches('/'))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix::fs::PermissionsExt, process};
+
+    use super::*;
+    use crate::{compiler, judger, models::JudgeStatus};
+
+    fn limit() -> ResourceLimit {
+        ResourceLimit::new(
+            Some(64_000_000u64.try_into().unwrap()),
+            Some(1000),
+            Some(2000),
+            None,
+            None,
+            None,
+        )
+    }
+
+    #[test]
+    fn builtin_covers_languages() {
+        let registry = LanguageRegistry::builtin();
+        for language in ["c", "cpp", "python3", "java", "go", "rust"] {
+            assert!(registry.get(language).is_some(), "{} is missing", language);
+        }
+    }
+
+    #[test]
+    fn native_runtimes_are_threaded() {
+        let registry = LanguageRegistry::builtin();
+        for language in ["go", "rust"] {
+            let run = registry.get(language).unwrap().get_run("/work/", &limit());
+            assert_eq!(run.scmp_policy, ScmpPolicy::Threaded, "{}", language);
+        }
+    }
+
+    #[test]
+    fn python_scales_limits() {
+        let python = LanguageRegistry::builtin().get("python3").unwrap();
+        let run = python.get_run("/work/", &limit());
+
+        assert_eq!(run.exe_path, "/usr/bin/python3");
+        assert_eq!(run.args, ["/work/main.pyc"]);
+        assert_eq!(run.scmp_policy, ScmpPolicy::Python);
+        assert_eq!(
+            run.resource_limit.memory.map(u64::from),
+            Some(2 * 64_000_000 + 32 * 1024 * 1024)
+        );
+        assert_eq!(run.resource_limit.cpu_time, Some(3 * 1000 + 2));
+        assert_eq!(run.resource_limit.real_time, Some(3 * 2000 + 2));
+    }
+
+    #[test]
+    fn java_compiles_into_workdir() {
+        let java = LanguageRegistry::builtin().get("java").unwrap();
+        let spec = java
+            .get_compile_spec(vec!["/work/Main.java".to_string()], "/work", limit())
+            .unwrap();
+
+        assert_eq!(spec.exe_path, "/work/Main.class");
+        assert_eq!(
+            spec.profile.compiler_path.to_str(),
+            Ok("/usr/lib/jvm/default-java/bin/javac")
+        );
+        assert!(
+            spec.profile
+                .args
+                .ends_with(&["-d".into(), "/work".into(), "{sources}".into()])
+        );
+    }
+
+    #[test]
+    fn java_sets_heap_from_memory() {
+        let java = LanguageRegistry::builtin().get("java").unwrap();
+        let run = java.get_run("/work", &limit());
+
+        assert!(run.args.contains(&"-Xmx61m".to_string()));
+        assert_eq!(run.args.last().map(String::as_str), Some("Main"));
+        assert_eq!(run.scmp_policy, ScmpPolicy::Java);
+        assert_eq!(
+            run.resource_limit.memory.map(u64::from),
+            Some(64_000_000 + 192 * 1024 * 1024)
+        );
+
+        let unlimited = ResourceLimit::new(None, None, None, None, None, None);
+        let run = java.get_run("/work", &unlimited);
+        assert!(!run.args.iter().any(|arg| arg.starts_with("-Xmx")));
+    }
+
+    #[test]
+    fn adjust_keeps_user_args() {
+        let python = LanguageRegistry::builtin().get("python3").unwrap();
+        let mut spec = JudgeSpec::try_new(
+            "/work/main.pyc",
+            None,
+            None,
+            None,
+            None,
+            vec!["--flag"],
+            vec![],
+            ScmpPolicy::Unsafe,
+            limit(),
+        )
+        .unwrap();
+        python.adjust(&mut spec, "/work").unwrap();
+
+        assert_eq!(spec.exe_path.to_str(), Ok("/usr/bin/python3"));
+        let args: Vec<&str> = spec.args.iter().map(|arg| arg.to_str().unwrap()).collect();
+        assert_eq!(args, ["/usr/bin/python3", "/work/main.pyc", "--flag"]);
+        assert_eq!(spec.scmp_policy, ScmpPolicy::Python);
+        assert_eq!(spec.mounts, [Mount::bind("/work", "/work", true)]);
+        assert_eq!(spec.workdir.as_deref(), Some("/work"));
+    }
+
+    #[test]
+    fn python_runs_what_it_compiles() {
+        let workspace = env::temp_dir().join(format!("code-goat-python-{}", process::id()));
+        fs::create_dir_all(&workspace).expect("Failed to create workspace");
+        fs::set_permissions(&workspace, fs::Permissions::from_mode(0o777))
+            .expect("Failed to open up workspace");
+        let path = |name: &str| workspace.join(name).to_string_lossy().into_owned();
+        fs::write(path("main.py"), "print(int(input()) + 1)\n").expect("Failed to write source");
+        fs::write(path("input"), "1\n").expect("Failed to write input");
+        fs::write(path("answer"), "2\n").expect("Failed to write answer");
+
+        let python = LanguageRegistry::builtin().get("python3").unwrap();
+        let workdir = path("");
+        let compiled = compiler::compile(
+            python
+                .get_compile_spec(vec![path("main.py")], &workdir, limit())
+                .unwrap(),
+        );
+        let exe_path = compiled.exe_path.clone().unwrap_or_default();
+
+        let mut spec = JudgeSpec::try_new(
+            &exe_path,
+            Some(&path("input")),
+            Some(&path("answer")),
+            Some(&path("output")),
+            None,
+            vec![],
+            vec![],
+            ScmpPolicy::Unsafe,
+            limit(),
+        )
+        .unwrap();
+        Mount::add_binds(&mut spec.mounts, [workspace.as_path()], false);
+        python.adjust(&mut spec, &workdir).unwrap();
+        let result = judger::judge(spec);
+        let _ = fs::remove_dir_all(&workspace);
+
+        assert_eq!(
+            compiled.status,
+            JudgeStatus::Exited,
+            "{:?}",
+            compiled.message
+        );
+        assert_eq!(exe_path, python.get_exe_path(&workdir));
+        assert_eq!(result.status, JudgeStatus::Accepted, "{:?}", result.message);
+    }
+}
@@ -1,11 +1,24 @@
+mod checker;
+mod comparator;
+mod compiler;
+mod cpu_pool;
 mod ffi;
+mod interactor;
 mod judger;
+mod language;
 pub mod logger;
 mod models;
 mod runner;
 mod sandbox;
+mod scorer;
 
+pub use compiler::compile;
+pub use cpu_pool::{CpuLease, CpuPool, configure_cpu_pool};
 pub use ffi::*;
-pub use judger::judge;
+pub use judger::{judge, judge_batch};
+pub use language::{LanguageProfile, LanguageRegistry, LanguageRun, LimitScale};
 pub use models::*;
+pub use sandbox::configure_cgroup_parent;
 pub use sandbox::seccomp::ScmpPolicy;
+pub use sandbox::user::{IdMap, configure_id_map};
+pub use scorer::judge_problem;
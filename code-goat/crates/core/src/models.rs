@@ -1,11 +1,11 @@
-use std::{ffi::CString, iter, num::TryFromIntError};
+use std::{ffi::CString, iter, num::TryFromIntError, path::Path};
 
 use log::warn;
 use serde::Serialize;
 
-use crate::sandbox::seccomp::ScmpPolicy;
+use crate::sandbox::{MemoryEvents, seccomp::ScmpPolicy};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct JudgeSpec {
     /// Absolute path to the executable file.
     pub exe_path: CString,
@@ -35,6 +35,32 @@ pub struct JudgeSpec {
 
     /// The judging policy (resource usage limits) to apply.
     pub resource_limit: ResourceLimit,
+
+    /// How to compare the output with the answer.
+    pub compare_mode: CompareMode,
+
+    /// Checker to grade the output with instead of comparing it to the
+    /// answer with `compare_mode`.
+    pub checker: Option<HelperSpec>,
+
+    /// Interactor to run along with the program. If set, stdin/stdout of
+    /// the program are connected to stdout/stdin of the interactor.
+    pub interactor: Option<HelperSpec>,
+
+    /// Network the program can reach.
+    pub network: NetworkMode,
+
+    /// Optional namespaces to isolate the program with.
+    pub namespaces: Namespaces,
+
+    /// Root filesystem the program sees.
+    pub root: RootMode,
+
+    /// Filesystems to mount once the root is made read-only, in order.
+    pub mounts: Vec<Mount>,
+
+    /// Absolute path inside the sandbox to run the program in.
+    pub workdir: Option<String>,
 }
 
 impl<'a> JudgeSpec {
@@ -79,6 +105,14 @@ impl<'a> JudgeSpec {
             envs: envs_cstr,
             scmp_policy,
             resource_limit,
+            compare_mode: CompareMode::default(),
+            checker: None,
+            interactor: None,
+            network: NetworkMode::default(),
+            namespaces: Namespaces::default(),
+            root: RootMode::default(),
+            mounts: vec![],
+            workdir: None,
         })
     }
 
@@ -113,11 +147,200 @@ impl<'a> JudgeSpec {
             envs,
             scmp_policy,
             resource_limit,
+            compare_mode: CompareMode::default(),
+            checker: None,
+            interactor: None,
+            network: NetworkMode::default(),
+            namespaces: Namespaces::default(),
+            root: RootMode::default(),
+            mounts: vec![],
+            workdir: None,
+        }
+    }
+}
+
+/// Network the runner can reach. Every runner gets its own network
+/// namespace, so that it can never reach the host or the internet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkMode {
+    /// No network interface at all.
+    #[default]
+    Isolated,
+    /// Only the loopback interface, for socket programming problems.
+    Loopback,
+}
+
+/// Root filesystem of the runner. Either way, the root is read-only and
+/// only [`JudgeSpec::mounts`] can be writable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RootMode {
+    /// A fresh root with only an allow-list of host directories, so that
+    /// directories added to the host never leak into the sandbox.
+    Minimal,
+    /// The root of the host with a deny-list of directories masked.
+    #[default]
+    Masked,
+}
+
+/// A filesystem to mount into the sandbox.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mount {
+    /// Absolute path inside the sandbox, without `..` components.
+    pub target: String,
+
+    pub kind: MountKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MountKind {
+    /// A file or directory of the host.
+    Bind { source: String, read_only: bool },
+    /// An empty writable tmpfs of at most `size` bytes.
+    Tmpfs { size: u64 },
+}
+
+impl Mount {
+    pub fn bind(source: &str, target: &str, read_only: bool) -> Self {
+        Self {
+            target: target.to_string(),
+            kind: MountKind::Bind {
+                source: source.to_string(),
+                read_only,
+            },
         }
     }
+
+    pub fn tmpfs(target: &str, size: u64) -> Self {
+        Self {
+            target: target.to_string(),
+            kind: MountKind::Tmpfs { size },
+        }
+    }
+
+    /// Bind each of `dirs` onto itself, skipping relative paths and those
+    /// `mounts` already has a mount at.
+    pub(crate) fn add_binds<'d>(
+        mounts: &mut Vec<Mount>,
+        dirs: impl IntoIterator<Item = &'d Path>,
+        read_only: bool,
+    ) {
+        for dir in dirs.into_iter().filter(|dir| dir.is_absolute()) {
+            let dir = dir.to_string_lossy();
+            if !mounts.iter().any(|mount| mount.target == dir) {
+                mounts.push(Mount::bind(&dir, &dir, read_only));
+            }
+        }
+    }
+}
+
+/// Namespaces to isolate the runner with, on top of the user, pid, mount,
+/// uts and network namespaces that are always isolated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Namespaces {
+    /// Hide System V IPC objects and POSIX message queues of the host.
+    pub ipc: bool,
+
+    /// Hide the cgroup hierarchy of the host, e.g. in `/proc/self/cgroup`.
+    pub cgroup: bool,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            ipc: true,
+            cgroup: true,
+        }
+    }
+}
+
+/// How the built-in grader compares the output with the answer.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CompareMode {
+    /// Compare line by line, ignoring trailing whitespace of each line
+    /// and trailing empty lines.
+    #[default]
+    Lines,
+    /// Compare byte by byte.
+    Exact,
+    /// Compare whitespace-separated tokens.
+    Tokens,
+    /// Compare like [`CompareMode::Lines`], ignoring case.
+    CaseInsensitive,
+    /// Compare like [`CompareMode::Lines`], ignoring the order of lines.
+    UnorderedLines,
+    /// Compare tokens, accepting numbers within either absolute or
+    /// relative error of the answer.
+    Float { abs_eps: f64, rel_eps: f64 },
+}
+
+/// A trusted helper program (e.g. a checker or an interactor) that is run by the judger
+/// inside the same sandbox as submissions.
+#[derive(Debug, Clone)]
+pub struct HelperSpec {
+    /// Absolute path to the executable file.
+    pub exe_path: CString,
+
+    /// Seccomp rule set name.
+    pub scmp_policy: ScmpPolicy,
+
+    /// The judging policy (resource usage limits) to apply.
+    pub resource_limit: ResourceLimit,
+}
+
+impl HelperSpec {
+    pub fn try_new(
+        exe_path: &str,
+        scmp_policy: ScmpPolicy,
+        resource_limit: ResourceLimit,
+    ) -> Result<Self, std::ffi::NulError> {
+        Ok(Self {
+            exe_path: CString::new(exe_path)?,
+            scmp_policy,
+            resource_limit,
+        })
+    }
+}
+
+/// How to compile source files into an executable.
+#[derive(Debug, Clone)]
+pub struct CompileProfile {
+    /// Absolute path to the compiler.
+    pub compiler_path: CString,
+
+    /// List of arguments to pass to the compiler. `{sources}` expands to
+    /// the source files and `{exe}` to the executable to produce.
+    /// Example: ["-O2", "-o", "{exe}", "{sources}"]
+    pub args: Vec<String>,
+
+    /// Environment variables to set for the compiler.
+    /// Example: ["PATH=/usr/bin"]
+    pub envs: Vec<String>,
+
+    /// Seccomp rule set name.
+    pub scmp_policy: ScmpPolicy,
+
+    /// The resource usage limits to apply to the whole compilation.
+    pub resource_limit: ResourceLimit,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct CompileSpec {
+    /// Absolute paths to the source files.
+    pub source_paths: Vec<String>,
+
+    /// Absolute path to the executable to produce. Its directory must be
+    /// writable inside the sandbox, as compilers keep temporary files there.
+    pub exe_path: String,
+
+    /// Absolute path to save the output of the compiler to.
+    pub diagnostics_path: String,
+
+    /// The compiler to run.
+    pub profile: CompileProfile,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ResourceLimit {
     /// Peak memory usage in bytes.
     pub memory: Option<U63>,
@@ -131,11 +354,23 @@ pub struct ResourceLimit {
     /// Upper limit to stack size in bytes.
     pub stack: Option<u32>,
 
-    /// Maximum number of process.
+    /// Maximum number of processes (and threads) of the run at once.
     pub n_process: Option<u16>,
 
     /// Upper limit to output size in bytes.
     pub output: Option<u32>,
+
+    /// CPUs to pin the run to, in the format of `cpuset.cpus` (e.g. "3"
+    /// or "2-3"). If unset, a core is leased from the configured
+    /// [`crate::CpuPool`], if any.
+    pub cpus: Option<String>,
+
+    /// Size of the scratch tmpfs mounted at `/tmp` for the run in bytes.
+    /// No scratch directory is mounted if unset.
+    ///
+    /// Pages of the tmpfs are charged to the memory cgroup of the run, so
+    /// files kept there count towards [`ResourceLimit::memory`] as well.
+    pub scratch: Option<u64>,
 }
 
 impl ResourceLimit {
@@ -154,6 +389,8 @@ impl ResourceLimit {
             stack,
             n_process,
             output,
+            cpus: None,
+            scratch: None,
         }
     }
 }
@@ -162,7 +399,7 @@ impl ResourceLimit {
 ///
 /// Use this to represent [`i64`] values that are guaranteed to be
 /// non-negative.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Serialize)]
 pub struct U63(u64);
 
 impl U63 {
@@ -238,26 +475,205 @@ pub struct JudgeResult {
 
     /// Resource usage statistics.
     pub resource_usage: Option<ResourceUsage>,
+
+    /// Fraction of the points earned in [0, 1], if partially correct.
+    pub score: Option<f64>,
+
+    /// Network the program was isolated with, if it was run.
+    pub network: Option<NetworkMode>,
 }
 
-#[derive(Debug, PartialEq, Eq, Serialize)]
+impl JudgeResult {
+    /// Create a result for a request that could not be judged.
+    pub fn from_error(message: String) -> Self {
+        Self {
+            status: JudgeStatus::InternalError,
+            message: Some(message),
+            exit_code: None,
+            signal: None,
+            resource_usage: None,
+            score: None,
+            network: None,
+        }
+    }
+
+    /// Fraction of the points earned in [0, 1].
+    pub fn get_score(&self) -> f64 {
+        match self.status {
+            JudgeStatus::PartiallyCorrect => self.score.unwrap_or(0.0),
+            status if status.is_passed() => 1.0,
+            _ => 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub enum JudgeStatus {
     Exited,
     Accepted,
     WrongAnswer,
+    PartiallyCorrect,
+    PresentationError,
     CpuTimeLimitExceeded,
     RealTimeLimitExceeded,
     MemoryLimitExceeded,
-    // TODO: OutputLimitExceeded,
+    OutputLimitExceeded,
+    ProcessLimitExceeded,
+    ScratchLimitExceeded,
     RuntimeError,
+    SecurityViolation,
+    CompileError,
+    CheckerError,
     InternalError,
 }
 
+impl JudgeStatus {
+    /// Whether the run passed, i.e. it was accepted or exited normally
+    /// without an answer to grade against.
+    pub fn is_passed(&self) -> bool {
+        matches!(self, JudgeStatus::Accepted | JudgeStatus::Exited)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CompileResult {
+    /// `JudgeStatus::Exited` if the executable was produced,
+    /// `JudgeStatus::CompileError` otherwise.
+    pub status: JudgeStatus,
+
+    /// Diagnostics of the compiler (e.g. errors and warnings).
+    pub message: Option<String>,
+
+    /// Absolute path to the produced executable, which can be passed to
+    /// [`JudgeSpec`] as it is.
+    pub exe_path: Option<String>,
+
+    /// Resource usage statistics.
+    pub resource_usage: Option<ResourceUsage>,
+}
+
+impl CompileResult {
+    /// Create a result for a request that could not be compiled.
+    pub fn from_error(message: String) -> Self {
+        Self {
+            status: JudgeStatus::InternalError,
+            message: Some(message),
+            exe_path: None,
+            resource_usage: None,
+        }
+    }
+}
+
+/// A single (input, answer) pair to judge in [`crate::judge_batch`].
+#[derive(Debug, Clone)]
+pub struct Testcase {
+    /// Absolute path to the input file (for stdin redirection).
+    pub input_path: Option<String>,
+
+    /// Absolute path to the answer file to grade the output against.
+    pub answer_path: Option<String>,
+}
+
+impl Testcase {
+    pub fn new(input_path: Option<&str>, answer_path: Option<&str>) -> Self {
+        Self {
+            input_path: input_path.map(String::from),
+            answer_path: answer_path.map(String::from),
+        }
+    }
+}
+
+/// A group of testcases scored together.
+#[derive(Debug, Clone)]
+pub struct Subtask {
+    /// Points of the whole subtask.
+    pub points: f64,
+
+    /// How to score the subtask from the scores of its testcases.
+    pub aggregation: Aggregation,
+
+    pub testcases: Vec<Testcase>,
+}
+
+/// How to score a subtask from the scores of its testcases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    /// Full points only if every testcase is accepted.
+    AllOrNothing,
+    /// Points scaled by the lowest score of the testcases.
+    Min,
+    /// Points split evenly over the testcases.
+    Sum,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubtaskReport {
+    /// Points earned.
+    pub score: f64,
+
+    /// Points of the whole subtask.
+    pub points: f64,
+
+    /// Results of the judged testcases, in order.
+    pub results: Vec<JudgeResult>,
+
+    /// Number of testcases skipped after the score of the subtask was
+    /// decided.
+    pub skipped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProblemReport {
+    /// Points earned over all subtasks.
+    pub score: f64,
+
+    /// Points of the whole problem.
+    pub points: f64,
+
+    pub subtasks: Vec<SubtaskReport>,
+
+    /// Descriptive message if the problem could not be judged.
+    pub message: Option<String>,
+}
+
+impl ProblemReport {
+    /// Create a report for a request that could not be judged.
+    pub fn from_error(message: String) -> Self {
+        Self {
+            score: 0.0,
+            points: 0.0,
+            subtasks: vec![],
+            message: Some(message),
+        }
+    }
+}
+
+/// Decides whether batch judging goes on after a testcase fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Stop at the first testcase that is not passed.
+    StopOnFailure,
+    /// Judge every testcase regardless of the previous results.
+    RunAll,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum InternalError {
     #[error("Failed to initialize cgroup: {0}")]
     CreateCgroup(cgroups_rs::fs::error::Error),
 
+    #[error("Invalid cgroup parent: '{0}'")]
+    CgroupParent(String),
+
+    #[error("Invalid cpu list: '{0}'")]
+    CpuList(String),
+
+    #[error("Invalid id map: {0}")]
+    IdMap(String),
+
+    #[error("Failed to write id map: {0}")]
+    WriteIdMap(std::io::Error),
+
     #[error("Failed to add process to cgroup: {0}")]
     AddToCgroup(cgroups_rs::fs::error::Error),
 
@@ -267,12 +683,24 @@ pub enum InternalError {
     #[error("Failed to read cpu stats from cgroup")]
     ReadCgroupCpuStats,
 
+    #[error("Failed to open file {path}: {source}")]
+    OpenFile { path: String, source: nix::Error },
+
+    #[error("Invalid mounts: {0}")]
+    Mounts(nix::Error),
+
     #[error("Failed to clone: {0}")]
     Clone(nix::Error),
 
     #[error("Failed to notify via channel: {0}")]
     Notify(std::io::Error),
 
+    #[error("Failed to compile seccomp filter: {0}")]
+    CompileSeccomp(String),
+
+    #[error("Failed to take over seccomp listener: {0}")]
+    MonitorSeccomp(nix::Error),
+
     #[error("Ended up in an unsupported wait status: {0}")]
     UnsupportedWait(String),
 
@@ -282,6 +710,27 @@ pub enum InternalError {
     #[error("Failed to read output: {0}")]
     ReadOutput(std::io::Error),
 
+    #[error("Checker requires an input file")]
+    CheckerInput,
+
+    #[error("Invalid checker argument: {0}")]
+    CheckerArgument(std::ffi::NulError),
+
+    #[error("Interactor requires an input and an output file")]
+    InteractorFiles,
+
+    #[error("Failed to join the interactor")]
+    JoinInteractor,
+
+    #[error("Invalid compiler argument: {0}")]
+    CompileArgument(std::ffi::NulError),
+
+    #[error("Failed to read compiler diagnostics: {0}")]
+    ReadDiagnostics(std::io::Error),
+
+    #[error("Failed to read checker message: {0}")]
+    ReadCheckerMessage(std::io::Error),
+
     #[error("I/O error occurred: {0}")]
     Io(#[from] std::io::Error),
 }
@@ -296,6 +745,21 @@ pub struct ResourceUsage {
 
     /// Real time used in milliseconds.
     pub real_time: u32,
+
+    /// Peak number of processes, if the kernel reports it.
+    pub n_process: Option<u64>,
+
+    /// Bytes used in the scratch directory, if mounted.
+    pub scratch: Option<u64>,
+
+    /// Memory events of the cgroup, to classify `MemoryLimitExceeded`.
+    #[serde(skip)]
+    pub(crate) memory_events: Option<MemoryEvents>,
+
+    /// Times creating a process failed at the limit, to classify
+    /// `ProcessLimitExceeded`.
+    #[serde(skip)]
+    pub(crate) process_failures: Option<u64>,
 }
 
 impl ResourceUsage {
@@ -304,6 +768,10 @@ impl ResourceUsage {
             memory,
             cpu_time,
             real_time,
+            n_process: None,
+            scratch: None,
+            memory_events: None,
+            process_failures: None,
         }
     }
 }
@@ -393,6 +861,35 @@ mod tests {
         }
     }
 
+    mod mount {
+        use super::*;
+
+        #[test]
+        fn add_binds_skips_existing_and_relative() {
+            let mut mounts = vec![Mount::bind("/work", "/work", false)];
+            let dirs = ["/work", "tests", "/tests", "/tests"].map(Path::new);
+            Mount::add_binds(&mut mounts, dirs, true);
+            assert_eq!(
+                mounts,
+                [
+                    Mount::bind("/work", "/work", false),
+                    Mount::bind("/tests", "/tests", true),
+                ]
+            );
+        }
+    }
+
+    mod network_mode {
+        use super::*;
+
+        #[test]
+        fn serializes_as_ffi_names() {
+            let names = [NetworkMode::Isolated, NetworkMode::Loopback]
+                .map(|network| serde_json::to_string(&network).unwrap());
+            assert_eq!(names, ["\"isolated\"", "\"loopback\""]);
+        }
+    }
+
     mod u63 {
         use super::*;
 
@@ -1,35 +1,225 @@
 use std::{
-    fs::File,
-    io::{ErrorKind, PipeReader, PipeWriter},
+    ffi::{CString, c_char},
+    io::{PipeReader, PipeWriter},
+    iter,
+    os::fd::{AsRawFd, OwnedFd},
+    path::Path,
+    ptr,
 };
 
-use libseccomp::error::SeccompErrno;
-use log::error;
+use log::warn;
 use nix::{
-    sched::{self, CloneFlags},
-    sys::signal::Signal,
+    errno::Errno,
+    fcntl::{self, OFlag},
+    libc, sched,
+    sys::{
+        signal::{self, Signal},
+        stat::Mode,
+        wait,
+    },
     unistd::{self, Pid},
 };
 
 use crate::{
-    models::{InternalError, JudgeSpec},
-    sandbox::{self, seccomp},
+    models::{InternalError, JudgeSpec, NetworkMode},
+    sandbox::{
+        self, CgroupSandbox, SandboxPlan,
+        seccomp::{self, ScmpProgram},
+        user::{self, IdMap},
+    },
 };
 
-/// Clone a new process with specified namespaces.
+/// Descriptors to redirect the standard streams of the runner to, in place
+/// of the files specified in [`JudgeSpec`] (e.g. pipes to an interactor).
+#[derive(Default)]
+pub(crate) struct RunnerIo {
+    pub(crate) stdin: Option<OwnedFd>,
+    pub(crate) stdout: Option<OwnedFd>,
+    pub(crate) stderr: Option<OwnedFd>,
+}
+
+impl RunnerIo {
+    /// Open the files of `spec` for the streams not given yet. They are
+    /// opened by the judger, so that the layout of the sandbox never
+    /// affects them and no symlink planted in place of a file is followed.
+    pub(crate) fn open_files(self, spec: &JudgeSpec) -> Result<Self, InternalError> {
+        const WRITE_FLAGS: OFlag = OFlag::O_WRONLY.union(OFlag::O_CREAT).union(OFlag::O_TRUNC);
+
+        let open = |fd: Option<OwnedFd>, path: &Option<String>, flags: OFlag| match (fd, path) {
+            (Some(fd), _) => Ok(Some(fd)),
+            (None, Some(path)) => open_file(path, flags).map(Some),
+            (None, None) => Ok(None),
+        };
+
+        Ok(Self {
+            stdin: open(self.stdin, &spec.input_path, OFlag::O_RDONLY)?,
+            stdout: open(self.stdout, &spec.output_path, WRITE_FLAGS)?,
+            stderr: open(self.stderr, &spec.error_path, WRITE_FLAGS)?,
+        })
+    }
+}
+
+/// Open `path` without following a symlink as its last component. The
+/// directory is given by the host and thus trusted, so it is resolved as
+/// usual, even if it lies under a symlink (e.g. `/tmp` on some systems).
+fn open_file(path: &str, flags: OFlag) -> Result<OwnedFd, InternalError> {
+    let to_error = |source| InternalError::OpenFile {
+        path: path.to_string(),
+        source,
+    };
+
+    let (dir, name) = match (Path::new(path).parent(), Path::new(path).file_name()) {
+        (Some(dir), Some(name)) if !dir.as_os_str().is_empty() => (dir, name),
+        (_, Some(name)) => (Path::new("."), name),
+        (_, None) => return Err(to_error(Errno::EINVAL)),
+    };
+    let dir = fcntl::open(
+        dir,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )
+    .map_err(to_error)?;
+
+    fcntl::openat(
+        &dir,
+        name,
+        flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC,
+        Mode::from_bits_truncate(0o644),
+    )
+    .map_err(to_error)
+}
+
+/// Pipes the runner uses to synchronize with the judger while setting up
+/// the sandbox.
+pub(crate) struct RunnerChannel {
+    /// Judger notifies the runner once its user and group are mapped.
+    pub(crate) id_map_rx: PipeReader,
+    /// Judger notifies the runner to execute the untrusted code.
+    pub(crate) setup_rx: PipeReader,
+    /// Runner sends the fd of its seccomp listener (or -1) to the judger.
+    pub(crate) notify_tx: PipeWriter,
+    /// Runner sends the reason of its abortion to the judger.
+    pub(crate) abort_tx: PipeWriter,
+}
+
+/// Everything the runner would otherwise allocate, prepared by the
+/// judger before cloning it.
+struct RunnerPlan {
+    sandbox: SandboxPlan,
+    scmp_program: Option<ScmpProgram>,
+    /// Null-terminated `argv` and `envp` of `execve`, pointing into the spec.
+    args: Vec<*const c_char>,
+    envs: Vec<*const c_char>,
+    /// Message to abort with if `execve` fails.
+    exec_error: String,
+}
+
+impl RunnerPlan {
+    fn new(spec: &JudgeSpec) -> Result<Self, InternalError> {
+        let to_pointers = |strings: &[CString]| {
+            strings
+                .iter()
+                .map(|string| string.as_ptr())
+                .chain(iter::once(ptr::null()))
+                .collect()
+        };
+
+        Ok(Self {
+            sandbox: sandbox::plan_sandbox(
+                spec.root,
+                &spec.mounts,
+                spec.resource_limit.scratch,
+                spec.workdir.as_deref(),
+            )
+            .map_err(InternalError::Mounts)?,
+            scmp_program: seccomp::compile_filter(&spec.scmp_policy, &spec.exe_path)?,
+            args: to_pointers(&spec.args),
+            envs: to_pointers(&spec.envs),
+            exec_error: format!("Failed to execute spec {:#?}.", spec),
+        })
+    }
+}
+
+/// `CLONE_INTO_CGROUP` flag of `clone3`, available since Linux 5.7.
+const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
+/// Arguments of `clone3`, laid out as `struct clone_args` of Linux.
+#[repr(C)]
+#[derive(Default)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+    cgroup: u64,
+}
+
+/// Clone a new process with specified namespaces into `cg_sandbox`.
 /// Returns the PID of the cloned process.
+///
+/// On cgroup v2, the process is spawned inside the cgroup so that it is
+/// accounted from its first instruction. Otherwise, or if the kernel does
+/// not support `clone3`, it is moved into the cgroup right after cloning.
+///
+/// Descriptors in `io` and `channel` are closed in the judger once the
+/// runner is cloned, so that the runner holds the only copies of them.
+/// `io` must already hold every stream to redirect; see
+/// [`RunnerIo::open_files`].
+///
+/// The runner starts as a copy of the judger, whose other threads (e.g.
+/// of other runs, or of the host process calling through FFI) may hold a
+/// lock of the allocator or the logger that the copy would wait for
+/// forever. So the runner never allocates nor logs until `execve`, and
+/// whatever it needs is prepared in [`RunnerPlan`] beforehand.
 pub fn clone(
     spec: &JudgeSpec,
-    setup_rx: PipeReader,
-    abort_tx: PipeWriter,
+    io: RunnerIo,
+    channel: RunnerChannel,
+    cg_sandbox: &CgroupSandbox,
+    id_map: &IdMap,
 ) -> Result<Pid, InternalError> {
-    let runner = {
-        Box::new(|| match run(&spec, &setup_rx, &abort_tx) {
-            Ok(status) => status,
-            Err(e) => e as isize,
-        })
+    let mut plan = RunnerPlan::new(spec)?;
+    let mut runner = || match run(&spec, &io, &channel, id_map, &mut plan) {
+        Ok(status) => status,
+        Err(e) => e as isize,
     };
 
+    let flags = sandbox::get_clone_flags(&spec.namespaces);
+
+    if let Some(cgroup_dir) = cg_sandbox.open_dir() {
+        let args = CloneArgs {
+            flags: flags.bits() as u64 | CLONE_INTO_CGROUP,
+            // Let parent notified when cloned process is terminated.
+            exit_signal: Signal::SIGCHLD as u64,
+            cgroup: cgroup_dir.as_raw_fd() as u64,
+            ..Default::default()
+        };
+
+        // Without a new stack, the child continues on its own copy of the
+        // parent's stack as if it were forked.
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_clone3,
+                &args as *const CloneArgs,
+                size_of::<CloneArgs>(),
+            )
+        };
+        match Errno::result(res) {
+            Ok(0) => unsafe { libc::_exit(runner() as i32) },
+            Ok(pid) => return Ok(Pid::from_raw(pid as i32)),
+            Err(e) => warn!(
+                "Failed to clone runner into its cgroup: {}; falling back...",
+                e
+            ),
+        }
+    }
+
     // `unistd::clone` requires a stack pointer, so we allocate the stack
     // on the heap. However, since a new stack is allocated again when
     // `unistd::execv` is executed after specifying the stack during clone,
@@ -37,120 +227,173 @@ pub fn clone(
     const STACK_SIZE: usize = 1024 * 1024; // 1MB
     let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
 
-    let flags = CloneFlags::CLONE_NEWUSER
-        | CloneFlags::CLONE_NEWPID
-        | CloneFlags::CLONE_NEWNS
-        | CloneFlags::CLONE_NEWUTS;
-
     // Let parent notified when cloned process is terminated.
-    let signal = Some(Signal::SIGCHLD as i32);
+    let exit_signal = Some(Signal::SIGCHLD as i32);
+
+    let pid = unsafe { sched::clone(Box::new(runner), &mut stack, flags, exit_signal) }
+        .map_err(InternalError::Clone)?;
 
-    // Return the PID of the cloned process.
-    unsafe { sched::clone(runner, &mut stack, flags, signal) }.map_err(InternalError::Clone)
+    // Runner must not execute the untrusted code outside of the cgroup.
+    if let Err(e) = cg_sandbox.add_process(pid) {
+        let _ = signal::kill(pid, Signal::SIGKILL);
+        let _ = wait::waitpid(pid, None);
+        return Err(e);
+    }
+    Ok(pid)
 }
 
 /// The function executed in the cloned child process.
 /// Run the untrusted code in an isolated environment
-/// and return the exit status.
+/// and return the exit status. It must neither allocate nor log; see
+/// [`clone`].
 fn run(
     spec: &JudgeSpec,
-    setup_rx: &PipeReader,
-    abort_tx: &PipeWriter,
+    io: &RunnerIo,
+    channel: &RunnerChannel,
+    id_map: &IdMap,
+    plan: &mut RunnerPlan,
 ) -> Result<isize, nix::Error> {
     // If any sandboxing mechanism fails, abort runner process with message.
     // Judger will collect the message and handle this request as a
     // `JudgeStatus::InternalError`.
     let abort = |e: nix::Error, message: &str| {
-        let _ = unistd::write(&abort_tx, message.as_bytes());
-
-        error!("{}; aborting runner...", message);
+        let _ = unistd::write(&channel.abort_tx, message.as_bytes());
         Err(e)
     };
 
-    if let Err(e) = sandbox::mount_sandbox() {
+    // Wait until judger maps the user and group of runner.
+    match unistd::read(&channel.id_map_rx, &mut [0u8; 1]) {
+        Ok(1) => {}
+        Ok(_) => return abort(Errno::ECANCELED, "Judger failed to map user"),
+        Err(e) => return abort(e, "Failed to get notified of user mapping"),
+    }
+
+    if let Err(e) = redirect(io) {
+        return abort(e.source, e.context);
+    }
+
+    // Switch to the mapped user first, so that files created for the
+    // sandbox have an owner in its namespace. Capabilities of runner in
+    // the namespace are kept until `execve`.
+    if let Err(e) = user::switch_user(id_map) {
+        return abort(e, "Failed to switch to mapped user");
+    }
+
+    if let Err(e) = sandbox::mount_sandbox(&mut plan.sandbox) {
         return abort(e, "Failed to mount user namespace");
     }
 
+    if spec.network == NetworkMode::Loopback
+        && let Err(e) = sandbox::bring_up_loopback()
+    {
+        return abort(e, "Failed to bring up loopback interface");
+    }
+
     if let Err(e) = sandbox::set_limit_to_sandbox(&spec.resource_limit) {
         return abort(e, "Failed to set resource limit");
     }
 
-    if let Err(e) = redirect(spec) {
-        return abort(e.source, &e.context);
+    // Keep every inherited descriptor from the untrusted code. This must
+    // precede seccomp, which does not allow `close_range`.
+    if let Err(e) = sandbox::close_inherited_fds() {
+        return abort(e, "Failed to close inherited descriptors");
     }
 
     // Apply seccomp right before `execve` so that runner can provoke
     // prohibited syscalls while creating the sandbox environment.
-    if let Err(e) = seccomp::apply_filter(&spec.scmp_policy, &spec.exe_path) {
-        let errno = e.errno().unwrap_or(SeccompErrno::EFAULT);
-        return abort(
-            nix::Error::from_raw(errno as i32),
-            "Failed to apply secure computing mode",
-        );
+    let notify_fd = match plan.scmp_program.as_ref().map(ScmpProgram::load) {
+        Some(Ok(notify_fd)) => notify_fd,
+        None => None,
+        Some(Err(e)) => return abort(e, "Failed to apply secure computing mode"),
+    };
+
+    // Let judger take over the seccomp listener to catch blocked syscalls.
+    let notify_fd_bytes = notify_fd.unwrap_or(-1).to_ne_bytes();
+    if let Err(e) = unistd::write(&channel.notify_tx, &notify_fd_bytes) {
+        return abort(e, "Failed to hand over seccomp listener");
     }
 
     // Wait until judger set up cgroups and timeout handler.
-    if let Err(e) = unistd::read(setup_rx, &mut [0u8; 1]) {
+    if let Err(e) = unistd::read(&channel.setup_rx, &mut [0u8; 1]) {
         return abort(e, "Failed to get notified");
     }
 
+    // The untrusted code must never answer its own seccomp notifications.
+    if let Some(notify_fd) = notify_fd
+        && let Err(e) = unistd::close(notify_fd)
+    {
+        return abort(e, "Failed to close seccomp listener");
+    }
+
     // Run the untrusted code in a sandboxed environment.
-    let Err(e) = unistd::execve(&spec.exe_path, &spec.args, &spec.envs);
-    return abort(e, &format!("Failed to execute spec {:#?}.", &spec));
+    unsafe {
+        libc::execve(
+            spec.exe_path.as_ptr(),
+            plan.args.as_ptr(),
+            plan.envs.as_ptr(),
+        )
+    };
+    return abort(Errno::last(), &plan.exec_error);
 }
 
 struct RedirectError {
     source: nix::Error,
-    context: String,
+    context: &'static str,
 }
 
 impl RedirectError {
-    fn from_io_error(io_error: std::io::Error, action: &str, file_path: &str) -> Self {
-        let source = match io_error.kind() {
-            ErrorKind::NotFound => nix::Error::ENOENT,
-            ErrorKind::PermissionDenied => nix::Error::EACCES,
-            ErrorKind::IsADirectory => nix::Error::EISDIR,
-            _ => nix::Error::UnknownErrno,
-        };
-
-        Self {
-            source,
-            context: format!(
-                "Failed to {} file {}: {}",
-                action,
-                file_path,
-                io_error.kind()
-            ),
-        }
-    }
-
-    fn from_errno(errno: nix::Error, fd: &str) -> Self {
+    fn from_errno(errno: nix::Error, context: &'static str) -> Self {
         Self {
             source: errno,
-            context: format!("Failed to redirect {}", fd),
+            context,
         }
     }
 }
 
-/// Redirect stdin, stdout, stderr according to the JudgeSpec.
-fn redirect<'a>(spec: &'a JudgeSpec) -> Result<(), RedirectError> {
-    if let Some(path) = &spec.input_path {
-        let f_in =
-            File::open(path.clone()).map_err(|e| RedirectError::from_io_error(e, "open", &path))?;
-        unistd::dup2_stdin(f_in).map_err(|e| RedirectError::from_errno(e, "stdin"))?;
+/// Redirect stdin, stdout, stderr to the descriptors in `io`.
+fn redirect(io: &RunnerIo) -> Result<(), RedirectError> {
+    if let Some(fd) = &io.stdin {
+        unistd::dup2_stdin(fd)
+            .map_err(|e| RedirectError::from_errno(e, "Failed to redirect stdin"))?;
     }
-
-    if let Some(path) = &spec.output_path {
-        let f_out = File::create(path.clone())
-            .map_err(|e| RedirectError::from_io_error(e, "create", &path))?;
-        unistd::dup2_stdout(f_out).map_err(|e| RedirectError::from_errno(e, "stdout"))?;
+    if let Some(fd) = &io.stdout {
+        unistd::dup2_stdout(fd)
+            .map_err(|e| RedirectError::from_errno(e, "Failed to redirect stdout"))?;
     }
-
-    if let Some(path) = &spec.error_path {
-        let f_err = File::create(path.clone())
-            .map_err(|e| RedirectError::from_io_error(e, "create", &path))?;
-        unistd::dup2_stderr(f_err).map_err(|e| RedirectError::from_errno(e, "stderr"))?;
+    if let Some(fd) = &io.stderr {
+        unistd::dup2_stderr(fd)
+            .map_err(|e| RedirectError::from_errno(e, "Failed to redirect stderr"))?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, os::unix, process};
+
+    use super::*;
+
+    #[test]
+    fn open_file_refuses_symlinked_file() {
+        let dir = env::temp_dir().join(format!("code-goat-open-{}", process::id()));
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/input"), b"1 2\n").unwrap();
+        unix::fs::symlink(dir.join("real/input"), dir.join("input")).unwrap();
+        unix::fs::symlink(dir.join("real"), dir.join("linked")).unwrap();
+
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let is_opened = |name: &str| open_file(&path(name), OFlag::O_RDONLY).is_ok();
+        let (real, last, parent) = (
+            is_opened("real/input"),
+            is_opened("input"),
+            is_opened("linked/input"),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(real);
+        assert!(!last);
+        // The directory is trusted, even if it is a symlink.
+        assert!(parent);
+    }
+}
@@ -1,55 +1,152 @@
+mod rootfs;
 pub(crate) mod seccomp;
+pub(crate) mod user;
 
 use std::{
-    cmp, env,
+    borrow::Cow,
+    cmp,
+    ffi::CStr,
+    fs::{self, File},
+    io::{self, Write},
+    mem,
     ops::{Add, Div},
-    path::Path,
-    sync::mpsc::{self, Sender},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::fs::FileExt,
+    },
+    path::{Component, Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+    },
     thread::{self, JoinHandle},
-    time::{Duration, Instant},
+    time::Duration,
 };
 
 use cgroups_rs::{
     CgroupPid,
     fs::{
-        Cgroup, cgroup_builder::CgroupBuilder, cpu::CpuController, hierarchies,
-        memory::MemController,
+        Cgroup, Controller, MaxValue, cgroup_builder::CgroupBuilder, cpu::CpuController,
+        cpuacct::CpuAcctController, cpuset::CpuSetController, hierarchies, memory::MemController,
+        pid::PidController,
     },
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use nix::{
+    errno::Errno,
+    fcntl::{self, OFlag},
+    libc,
     mount::{self, MsFlags},
+    sched::CloneFlags,
     sys::{
         resource::{self, Resource},
         signal::{self, Signal},
+        stat::Mode,
+        statvfs,
     },
     unistd::{self, Pid},
 };
+use parking_lot::Mutex;
 
-use crate::models::{InternalError, ResourceLimit};
+use crate::models::{InternalError, Mount, Namespaces, ResourceLimit, RootMode};
+
+/// Where the scratch tmpfs of [`ResourceLimit::scratch`] is mounted.
+pub(crate) const SCRATCH_DIR: &str = "/tmp";
 
 const MEBI_BYTE: u32 = 1 << 10 << 10;
 const MEGA_BYTE: u32 = 1000 * 1000;
 
+/// Parent of the cgroups created for each run, relative to the root of
+/// the cgroup hierarchy.
+static CGROUP_PARENT: Mutex<Cow<'static, str>> = Mutex::new(Cow::Borrowed("code-goat"));
+
+/// Number of cgroups created by this process, used to name them uniquely.
+static CGROUP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Set the parent of the cgroups created for each run. The parent is
+/// created if it does not exist, and is never deleted by the judger.
+pub fn configure_cgroup_parent(parent: &str) -> Result<(), InternalError> {
+    let parent = parent.trim_matches('/');
+    let is_valid = !parent.is_empty()
+        && Path::new(parent)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+    if !is_valid {
+        return Err(InternalError::CgroupParent(parent.to_string()));
+    }
+
+    *CGROUP_PARENT.lock() = Cow::Owned(parent.to_string());
+    info!("Configured cgroup parent to '{}'.", parent);
+    Ok(())
+}
+
+/// Read the memory nodes of the host. A cpuset of cgroup v1 takes no
+/// process until its memory nodes are set, while one of cgroup v2 uses
+/// those of its parent.
+fn read_host_mems() -> Option<String> {
+    if hierarchies::is_cgroup2_unified_mode() {
+        return None;
+    }
+    let path = hierarchies::auto().root().join("cpuset/cpuset.mems");
+    fs::read_to_string(&path)
+        .inspect_err(|e| warn!("Failed to read {}: {}", path.display(), e))
+        .ok()
+        .map(|mems| mems.trim().to_string())
+}
+
 pub(crate) struct CgroupSandbox {
     inner: Cgroup,
+    /// Cumulative counters left by the previous runs in this cgroup.
+    baseline: Counters,
+    /// `memory.peak` of cgroup v2, reset through this very fd for each
+    /// run after the first one.
+    memory_peak: Option<File>,
 }
 
-impl CgroupSandbox {
-    const CGROUP_NAME: &str = "code-goat";
+/// Counters of a cgroup that only grow across the runs in it.
+#[derive(Debug, Clone, Copy, Default)]
+struct Counters {
+    cpu_time_in_us: u64,
+    memory_events: MemoryEvents,
+    process_failures: u64,
+}
 
+impl CgroupSandbox {
+    /// Create a cgroup for a single run. Its name is unique among every
+    /// judger sharing the parent, so that concurrent runs never share
+    /// statistics or delete each other's cgroup.
     pub(crate) fn new(resource_limit: &ResourceLimit) -> Result<CgroupSandbox, InternalError> {
-        let builder = CgroupBuilder::new(Self::CGROUP_NAME)
+        let name = format!(
+            "{}/run-{}-{}",
+            CGROUP_PARENT.lock(),
+            process::id(),
+            CGROUP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        debug!("Creating cgroup {}", name);
+
+        let builder = CgroupBuilder::new(&name)
             // Forces processes in this cgroup to use CPU up to 100%.
             .cpu()
             .period(100 * 1000)
-            .quota(100 * 1000)
-            .done()
-            // Minimize memory swapping.
-            .memory()
-            .swappiness(0);
+            .quota(100 * 1000);
+
+        let builder = if let Some(cpus) = &resource_limit.cpus {
+            // Pin processes to the given cores for stable timing.
+            let builder = builder.cpus(cpus.clone());
+            match read_host_mems() {
+                Some(mems) => builder.mems(mems),
+                None => builder,
+            }
+        } else {
+            builder
+        }
+        .done()
+        // Minimize memory swapping.
+        .memory()
+        .swappiness(0);
 
-        let cgroup = if let Some(limit) = resource_limit.memory {
+        let builder = if let Some(limit) = resource_limit.memory {
             // Limit memory usage if specified.
             builder.memory_hard_limit(
                 // Add margin of 1MiB to detect MLE.
@@ -58,31 +155,180 @@ impl CgroupSandbox {
         } else {
             builder
         }
-        .done()
+        .done();
+
+        let cgroup = if let Some(limit) = resource_limit.n_process {
+            // Count only the processes of this run, unlike `RLIMIT_NPROC`
+            // which counts every process of the user on the host.
+            builder
+                .pid()
+                .maximum_number_of_processes(MaxValue::Value(limit.into()))
+                .done()
+        } else {
+            builder
+        }
         .build(hierarchies::auto())
         // Return error if cgroup creation fails.
         .map_err(InternalError::CreateCgroup)?;
 
-        Ok(CgroupSandbox { inner: cgroup })
+        Ok(CgroupSandbox {
+            inner: cgroup,
+            baseline: Counters::default(),
+            memory_peak: None,
+        })
     }
 
-    pub(crate) fn add_process(&self, pid: Pid) -> Result<(), InternalError> {
-        // TODO: delete clocks here after testing.
-        let clock = Instant::now();
+    /// Prepare this cgroup for another run with the same limits, so that
+    /// runs of one executable set up a cgroup only once. Peaks are reset
+    /// and cumulative counters are measured from here on.
+    ///
+    /// Returns `false` if the next run could not be told apart from the
+    /// previous ones, e.g. because a process of them is still alive or
+    /// the kernel cannot reset the peak memory usage. Such a cgroup must
+    /// not be reused, so every run gets a new cgroup on cgroup v2 of
+    /// kernels before 6.12.
+    pub(crate) fn reset(&mut self) -> bool {
+        if !self.inner.tasks().is_empty() {
+            warn!("Cgroup still has processes of the previous run.");
+            return false;
+        }
+        // `pids.peak` cannot be reset, but every run has at least one
+        // process, so a peak of one is also that of the next run.
+        if let Some(peak) = self.read_process_peak()
+            && peak > 1
+        {
+            debug!(
+                "Cgroup had {} processes at once in the previous runs.",
+                peak
+            );
+            return false;
+        }
+        self.reclaim_memory();
+        let is_peak_reset = match self.v2_dir() {
+            // A write resets the peak only for reads through the same fd,
+            // which kernels before 6.12 do not support.
+            Some(dir) => File::options()
+                .read(true)
+                .write(true)
+                .open(dir.join("memory.peak"))
+                .and_then(|mut peak| {
+                    peak.write_all(b"reset")?;
+                    self.memory_peak = Some(peak);
+                    Ok(())
+                }),
+            None => match self.inner.controller_of::<MemController>() {
+                Some(controller) => {
+                    fs::write(controller.path().join("memory.max_usage_in_bytes"), "0")
+                }
+                None => Err(io::ErrorKind::NotFound.into()),
+            },
+        };
+        if let Err(e) = is_peak_reset {
+            warn!("Failed to reset peak memory usage: {}", e);
+            return false;
+        }
 
-        let cgroup_pid = CgroupPid::from(pid.as_raw() as u64);
-        let res = self
-            .inner
-            .add_task_by_tgid(cgroup_pid)
-            .map_err(InternalError::AddToCgroup);
+        match self.read_counters() {
+            Ok(counters) => {
+                self.baseline = counters;
+                true
+            }
+            Err(e) => {
+                warn!("Failed to read counters of cgroup: {}", e);
+                false
+            }
+        }
+    }
+
+    /// Reclaim the page cache the previous runs left charged to this
+    /// cgroup, which the next run would otherwise be measured with. Some
+    /// kernel memory stays charged, which is far below [`MEBI_BYTE`] of
+    /// margin the memory limit has.
+    fn reclaim_memory(&self) {
+        let result = match self.v2_dir() {
+            // Fails once nothing more can be reclaimed, which is expected.
+            Some(dir) => {
+                let _ = fs::write(dir.join("memory.reclaim"), u64::MAX.to_string());
+                return;
+            }
+            None => match self.inner.controller_of::<MemController>() {
+                Some(controller) => fs::write(controller.path().join("memory.force_empty"), "0"),
+                None => return,
+            },
+        };
+        if let Err(e) = result {
+            warn!("Failed to reclaim memory of cgroup: {}", e);
+        }
+    }
+
+    /// Pin a reused cgroup to `cpus`, which may differ from the cores of
+    /// the previous run. Returns `false` if it could not be pinned.
+    pub(crate) fn pin(&self, cpus: Option<&str>) -> bool {
+        let Some(cpus) = cpus else {
+            return true;
+        };
+        self.inner
+            .controller_of::<CpuSetController>()
+            .map(|controller| controller.set_cpus(cpus))
+            .is_some_and(|result| {
+                result
+                    .inspect_err(|e| warn!("Failed to pin cgroup to cpus {}: {}", cpus, e))
+                    .is_ok()
+            })
+    }
+
+    fn read_counters(&self) -> Result<Counters, InternalError> {
+        Ok(Counters {
+            cpu_time_in_us: self.read_cpu_time_in_us()?,
+            memory_events: self.read_memory_events_total().unwrap_or_default(),
+            process_failures: self.read_process_failures_total().unwrap_or_default(),
+        })
+    }
+
+    /// Open the directory of this cgroup to clone a process straight into
+    /// it. Only cgroup v2 supports `CLONE_INTO_CGROUP`.
+    pub(crate) fn open_dir(&self) -> Option<File> {
+        let path = self.v2_dir()?;
+        File::open(&path)
+            .inspect_err(|e| warn!("Failed to open cgroup {}: {}", path.display(), e))
+            .ok()
+    }
 
-        let duration = clock.elapsed();
-        debug!("Duration to add process to cgroup: {:?}", duration);
+    /// Directory of this cgroup, if it lives in a cgroup v2 hierarchy.
+    fn v2_dir(&self) -> Option<PathBuf> {
+        self.inner
+            .v2()
+            .then(|| hierarchies::auto().root().join(self.inner.path()))
+    }
 
-        res
+    pub(crate) fn add_process(&self, pid: Pid) -> Result<(), InternalError> {
+        let cgroup_pid = CgroupPid::from(pid.as_raw() as u64);
+        self.inner
+            .add_task_by_tgid(cgroup_pid)
+            .map_err(InternalError::AddToCgroup)
     }
 
+    /// Read the peak memory usage. `memory.peak` of cgroup v2 is preferred
+    /// because it is tracked by the kernel for the cgroup itself.
     pub(crate) fn read_memory_usage(&self) -> Result<u64, InternalError> {
+        if let Some(peak) = &self.memory_peak {
+            let mut buf = [0u8; 32];
+            let n = peak
+                .read_at(&mut buf, 0)
+                .or(Err(InternalError::ReadCgroupMemoryStats))?;
+            return String::from_utf8_lossy(&buf[..n])
+                .trim()
+                .parse()
+                .or(Err(InternalError::ReadCgroupMemoryStats));
+        }
+
+        if let Some(dir) = self.v2_dir()
+            && let Ok(peak) = fs::read_to_string(dir.join("memory.peak"))
+            && let Ok(peak) = peak.trim().parse()
+        {
+            return Ok(peak);
+        }
+
         let controller = self
             .inner
             .controller_of::<MemController>()
@@ -91,7 +337,62 @@ impl CgroupSandbox {
         Ok(controller.memory_stat().max_usage_in_bytes)
     }
 
+    /// Read `memory.events` of this run, which only cgroup v2 provides.
+    pub(crate) fn read_memory_events(&self) -> Option<MemoryEvents> {
+        let events = self.read_memory_events_total()?;
+        Some(events.since(&self.baseline.memory_events))
+    }
+
+    fn read_memory_events_total(&self) -> Option<MemoryEvents> {
+        let events = fs::read_to_string(self.v2_dir()?.join("memory.events"))
+            .inspect_err(|e| warn!("Failed to read memory events: {}", e))
+            .ok()?;
+        Some(MemoryEvents::parse(&events))
+    }
+
+    /// Read the peak number of processes from `pids.peak`, which only
+    /// cgroup v2 of recent kernels provides. It cannot be reset, so
+    /// [`Self::reset`] reuses a cgroup only while it is one.
+    pub(crate) fn read_process_peak(&self) -> Option<u64> {
+        let peak = fs::read_to_string(self.v2_dir()?.join("pids.peak")).ok()?;
+        peak.trim().parse().ok()
+    }
+
+    /// Read how many times creating a process failed at `pids.max`.
+    pub(crate) fn read_process_failures(&self) -> Option<u64> {
+        let failures = self.read_process_failures_total()?;
+        Some(failures.saturating_sub(self.baseline.process_failures))
+    }
+
+    /// Read the `max` counter of `pids.events`, which both cgroup v1 and
+    /// v2 provide.
+    fn read_process_failures_total(&self) -> Option<u64> {
+        self.inner
+            .controller_of::<PidController>()?
+            .get_pid_events()
+            .inspect_err(|e| warn!("Failed to read pids events: {}", e))
+            .ok()
+    }
+
+    /// Read the cpu time usage of this run in milliseconds.
     pub(crate) fn read_cpu_time_usage(&self) -> Result<u32, InternalError> {
+        let cpu_time_in_us = self
+            .read_cpu_time_in_us()?
+            .saturating_sub(self.baseline.cpu_time_in_us);
+        Ok((cpu_time_in_us / 1000).try_into().unwrap_or(u32::MAX))
+    }
+
+    /// Read the cpu time usage in microseconds from `cpu.stat`, or from
+    /// `cpuacct.usage` on cgroup v1, whose `cpu.stat` lacks `usage_usec`.
+    fn read_cpu_time_in_us(&self) -> Result<u64, InternalError> {
+        if !self.inner.v2() {
+            let controller = self
+                .inner
+                .controller_of::<CpuAcctController>()
+                .ok_or(InternalError::ReadCgroupCpuStats)?;
+            return Ok(controller.cpuacct().usage / 1000);
+        }
+
         let cpu = self
             .inner
             .controller_of::<CpuController>()
@@ -106,30 +407,60 @@ impl CgroupSandbox {
             .split_whitespace()
             .collect();
 
-        let cpu_time_in_us: u32 = cpu_stat
+        cpu_stat
             .get(1)
             .ok_or(InternalError::ReadCgroupCpuStats)?
             .parse()
-            .or(Err(InternalError::ReadCgroupCpuStats))?;
-
-        Ok(cpu_time_in_us / 1000)
+            .or(Err(InternalError::ReadCgroupCpuStats))
     }
 }
 
 impl Drop for CgroupSandbox {
     fn drop(&mut self) {
-        // TODO: delete clocks here after testing.
-        let clock = Instant::now();
+        match self.inner.delete() {
+            Ok(_) => debug!("Deleted cgroup successfully."),
+            Err(e) => error!("Failed to delete cgroup: {:?}", e),
+        }
+    }
+}
 
-        if let Err(e) = self.inner.delete() {
-            error!("Failed to delete cgroup: {:?}", e);
-            return;
-        } else {
-            debug!("Deleted cgroup successfully.");
+/// Counters of `memory.events` of a cgroup.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct MemoryEvents {
+    /// Times the usage was about to go over the limit and memory was
+    /// reclaimed, which includes reclaiming mere page cache.
+    pub(crate) max: u64,
+    /// Times an allocation failed at the limit even after reclaim.
+    pub(crate) oom: u64,
+    /// Processes killed by the OOM killer.
+    pub(crate) oom_kill: u64,
+}
+
+impl MemoryEvents {
+    /// Counters of the events that happened after `earlier`.
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            max: self.max.saturating_sub(earlier.max),
+            oom: self.oom.saturating_sub(earlier.oom),
+            oom_kill: self.oom_kill.saturating_sub(earlier.oom_kill),
         }
+    }
 
-        let duration = clock.elapsed();
-        debug!("Duration to delete cgroup: {:?}", duration,);
+    fn parse(events: &str) -> Self {
+        let mut parsed = Self::default();
+        for line in events.lines() {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            let counter = match key {
+                "max" => &mut parsed.max,
+                "oom" => &mut parsed.oom,
+                "oom_kill" => &mut parsed.oom_kill,
+                _ => continue,
+            };
+            *counter = value.trim().parse().unwrap_or(0);
+        }
+        parsed
     }
 }
 
@@ -173,17 +504,38 @@ impl Drop for TimeSandbox {
     }
 }
 
+/// Namespaces to clone the runner into.
+///
+/// A new cgroup namespace is rooted at the cgroup of the runner only if it
+/// is cloned straight into it; otherwise it is rooted at the cgroup of the
+/// judger, which still hides every cgroup above that.
+pub(crate) fn get_clone_flags(namespaces: &Namespaces) -> CloneFlags {
+    let mut flags = CloneFlags::CLONE_NEWUSER
+        | CloneFlags::CLONE_NEWPID
+        | CloneFlags::CLONE_NEWNS
+        | CloneFlags::CLONE_NEWUTS
+        | CloneFlags::CLONE_NEWNET;
+
+    if namespaces.ipc {
+        flags |= CloneFlags::CLONE_NEWIPC;
+    }
+    if namespaces.cgroup {
+        flags |= CloneFlags::CLONE_NEWCGROUP;
+    }
+    flags
+}
+
 const SENSITIVE_DIRS: [&str; 11] = [
     // NOTE: The following directories are not masked because they have...
     // "/bin",              // Core commands
     // "/lib", "/lib64"     // Shared libraries
-    // "/proc",             // Process and system information
+    // "/proc",             // Process and system information (replaced)
     // "/tmp",              // Temporary files
     // "/usr",              // User binaries and read-only data
     // "/var",              // Variable data files
     //
     "/boot", // Kernel images, GRUB configuration files, etc.
-    "/dev",  // Hardware devices as files
+    "/dev",  // Hardware devices as files (replaced with a minimal one)
     "/etc",  // System configuration files
     "/home", // User home directories
     "/mnt",  // Used by administrators for mounting filesystems
@@ -195,8 +547,48 @@ const SENSITIVE_DIRS: [&str; 11] = [
     "/sys",  // System and kernel information
 ];
 
-/// Mount runner process into a safe mount namespace.
-pub(crate) fn mount_sandbox() -> Result<(), nix::Error> {
+/// Mounts of the sandbox, planned by the judger with [`plan_sandbox`] and
+/// made by the runner with [`mount_sandbox`].
+pub(crate) struct SandboxPlan {
+    /// Planned only for [`RootMode::Minimal`].
+    minimal_root: Option<rootfs::MinimalRoot>,
+    mounts: Vec<rootfs::PlannedMount>,
+    dev: rootfs::DevPlan,
+    proc_dir: PathBuf,
+    workdir: Option<PathBuf>,
+}
+
+/// Plan to apply `mounts` and a scratch tmpfs of `scratch` bytes on top of
+/// the read-only root and enter `workdir`. Paths and options are built
+/// here, so that [`mount_sandbox`] never allocates.
+pub(crate) fn plan_sandbox(
+    root: RootMode,
+    mounts: &[Mount],
+    scratch: Option<u64>,
+    workdir: Option<&str>,
+) -> Result<SandboxPlan, nix::Error> {
+    let (minimal_root, root_dir) = match root {
+        RootMode::Minimal => (
+            Some(rootfs::MinimalRoot::plan()?),
+            Path::new(rootfs::NEW_ROOT),
+        ),
+        RootMode::Masked => (None, Path::new("/")),
+    };
+
+    // The scratch directory comes last so that no other mount covers it.
+    let scratch = scratch.map(|size| Mount::tmpfs(SCRATCH_DIR, size));
+    Ok(SandboxPlan {
+        minimal_root,
+        mounts: rootfs::plan_mounts(mounts.iter().chain(&scratch), root_dir)?,
+        dev: rootfs::DevPlan::new(root_dir.join("dev")),
+        proc_dir: root_dir.join("proc"),
+        workdir: workdir.map(PathBuf::from),
+    })
+}
+
+/// Mount runner process into a safe mount namespace as `plan` says. This
+/// runs in the runner, which must not allocate; see [`crate::runner::clone`].
+pub(crate) fn mount_sandbox(plan: &mut SandboxPlan) -> Result<(), nix::Error> {
     // Make mount namespace private to avoid affecting the host system.
     mount::mount(
         None::<&str>,
@@ -206,6 +598,28 @@ pub(crate) fn mount_sandbox() -> Result<(), nix::Error> {
         None::<&str>,
     )?;
 
+    // Open the sources first, as the minimal root hides the host.
+    rootfs::open_mounts(&mut plan.mounts)?;
+    plan.dev.open()?;
+    match &mut plan.minimal_root {
+        Some(minimal_root) => minimal_root.pivot(&plan.mounts, &plan.dev, &plan.proc_dir)?,
+        None => {
+            mask_host_root(&plan.dev, &plan.proc_dir)?;
+            rootfs::apply_mounts(&plan.mounts)?;
+        }
+    }
+
+    if let Some(workdir) = &plan.workdir {
+        unistd::chdir(workdir.as_path())?;
+    }
+
+    Ok(())
+}
+
+/// Make the root of the host read-only and mask [`SENSITIVE_DIRS`]. `/dev`
+/// and `/proc` are then replaced with `dev` and a procfs of the pid
+/// namespace of runner at `proc_dir`.
+fn mask_host_root(dev: &rootfs::DevPlan, proc_dir: &Path) -> Result<(), nix::Error> {
     // Remount root filesystem as read-only.
     mount::mount(
         Some("/"),
@@ -228,24 +642,121 @@ pub(crate) fn mount_sandbox() -> Result<(), nix::Error> {
         }
     }
 
-    // Remount working directory to be writable for logging.
-    if let Ok(workspace) = env::var("SANDBOX_WORKSPACE") {
-        let workspace = workspace.as_str();
-        mount::mount(
-            Some(workspace),
-            workspace,
-            None::<&str>,
-            MsFlags::MS_BIND | MsFlags::MS_REC,
-            None::<&str>,
-        )?;
-        unistd::chdir(workspace)?;
+    dev.mount()?;
+    rootfs::mount_proc(proc_dir)
+}
+
+/// Open the scratch directory of the runner `pid`, which keeps the tmpfs
+/// alive to be measured after the runner exits. The tmpfs is freed along
+/// with the file.
+pub(crate) fn open_scratch(pid: Pid) -> Option<File> {
+    let path = format!("/proc/{}/root{}", pid, SCRATCH_DIR);
+    File::open(&path)
+        .inspect_err(|e| warn!("Failed to open scratch directory {}: {}", path, e))
+        .ok()
+}
+
+/// Read how many bytes are used in the scratch directory.
+pub(crate) fn read_scratch_usage(scratch: &File) -> Option<u64> {
+    let stat = statvfs::fstatvfs(scratch)
+        .inspect_err(|e| warn!("Failed to stat scratch directory: {}", e))
+        .ok()?;
+    let used_blocks = stat.blocks().saturating_sub(stat.blocks_free());
+    Some(used_blocks * stat.fragment_size())
+}
+
+/// Bring up the loopback interface of the network namespace of runner,
+/// which starts down like every namespace created by `CLONE_NEWNET`.
+pub(crate) fn bring_up_loopback() -> Result<(), nix::Error> {
+    let socket = Errno::result(unsafe {
+        libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+    })?;
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+    let mut request: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dst = *src as libc::c_char;
     }
 
+    Errno::result(unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) })?;
+    unsafe {
+        request.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+    }
+    Errno::result(unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS, &request) })?;
+
     Ok(())
 }
 
-/// Set resource limits to the sandbox. Memory usage is not limited here
-/// because the work is done by [`CgroupSandbox`]. Add extra bytes/time to
+/// Mark every descriptor but the standard streams close-on-exec, so that
+/// none inherited from the judger (e.g. its pipes to the runner, log files
+/// or those of the host process calling through FFI) reaches the untrusted
+/// code. They stay open until `execve`, so that a failing `execve` can
+/// still be reported to the judger.
+pub(crate) fn close_inherited_fds() -> Result<(), nix::Error> {
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_close_range,
+            3,
+            libc::c_uint::MAX,
+            libc::CLOSE_RANGE_CLOEXEC,
+        )
+    };
+    match Errno::result(res) {
+        Ok(_) => Ok(()),
+        // `CLOSE_RANGE_CLOEXEC` is only available since Linux 5.11.
+        Err(Errno::ENOSYS | Errno::EINVAL) => set_cloexec_to_listed_fds(),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fallback of [`close_inherited_fds`] for older kernels. Entries of
+/// `/proc/self/fd` are read into a buffer on the stack, as the runner must
+/// not allocate.
+fn set_cloexec_to_listed_fds() -> Result<(), nix::Error> {
+    let dir = fcntl::open(
+        "/proc/self/fd",
+        OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = Errno::result(unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        })?;
+        if len == 0 {
+            return Ok(());
+        }
+
+        // Each entry is a `struct linux_dirent64`, whose length is at byte
+        // 16 and whose null-terminated name starts at byte 19.
+        let mut entries = &buf[..len as usize];
+        while let Some(length) = entries.get(16..18) {
+            let length = u16::from_ne_bytes([length[0], length[1]]) as usize;
+            let fd = entries
+                .get(19..length)
+                .and_then(|name| CStr::from_bytes_until_nul(name).ok())
+                .and_then(|name| name.to_str().ok()?.parse::<RawFd>().ok());
+            entries = entries.get(length.max(1)..).unwrap_or_default();
+
+            let Some(fd) = fd.filter(|&fd| fd > 2) else {
+                continue;
+            };
+            match Errno::result(unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) }) {
+                Ok(_) | Err(Errno::EBADF) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Set resource limits to the sandbox. Memory usage and the number of
+/// processes are not limited here because the work is done by
+/// [`CgroupSandbox`]. Add extra bytes/time to
 /// limit to avoid `JudgeStatus::RuntimeError` that cannot be traced.
 pub(crate) fn set_limit_to_sandbox(resource_limit: &ResourceLimit) -> Result<(), nix::Error> {
     if let Some(limit_ms) = resource_limit.cpu_time {
@@ -254,11 +765,6 @@ pub(crate) fn set_limit_to_sandbox(resource_limit: &ResourceLimit) -> Result<(),
         resource::setrlimit(Resource::RLIMIT_CPU, limit_s, limit_s)?;
     };
 
-    if let Some(limit) = resource_limit.n_process {
-        let limit = limit.into();
-        resource::setrlimit(Resource::RLIMIT_NPROC, limit, limit)?;
-    };
-
     if let Some(limit) = resource_limit.stack {
         let limit = limit.add(MEBI_BYTE).into();
         resource::setrlimit(Resource::RLIMIT_STACK, limit, limit)?;
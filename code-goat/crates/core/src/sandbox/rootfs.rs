@@ -0,0 +1,426 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{self, ffi::OsStrExt},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use nix::{
+    fcntl::{self, OFlag},
+    libc,
+    mount::{self, MntFlags, MsFlags},
+    sys::{
+        stat::{self, Mode, SFlag},
+        statvfs::{self, FsFlags},
+    },
+    unistd,
+};
+
+use crate::models::{Mount, MountKind};
+
+/// Host directories bound read-only into the minimal root. Those that are
+/// symlinks on the host (e.g. `/bin` on merged-usr systems) are recreated
+/// as symlinks instead.
+pub(crate) const ROOT_ALLOW_LIST: [&str; 4] = ["/usr", "/bin", "/lib", "/lib64"];
+
+/// Entries of `/etc` of the host bound read-only into the minimal root, as
+/// the runtimes of the language profiles read them. A trailing `*` matches
+/// any suffix, e.g. `java-*` for `/etc/java-17-openjdk` which the JDK links
+/// its configuration to.
+pub(crate) const ETC_ALLOW_LIST: [&str; 4] = ["java-*", "localtime", "passwd", "group"];
+
+/// Where the minimal root is assembled before `pivot_root`. It is covered
+/// by a tmpfs only inside the mount namespace of runner.
+pub(crate) const NEW_ROOT: &str = "/tmp";
+
+/// Device nodes of the host bound into `/dev` of the sandbox.
+pub(crate) const DEV_NODES: [&str; 4] = ["null", "zero", "random", "urandom"];
+
+/// Symlinks created in `/dev` of the sandbox, with their targets.
+pub(crate) const DEV_SYMLINKS: [(&str, &str); 4] = [
+    ("fd", "/proc/self/fd"),
+    ("stdin", "/proc/self/fd/0"),
+    ("stdout", "/proc/self/fd/1"),
+    ("stderr", "/proc/self/fd/2"),
+];
+
+/// A path of the host held open, so that it can still be bound once the
+/// host root is masked or unreachable. The runner opens it itself, as a
+/// bind must come from a mount of its own namespace.
+struct Source {
+    path: PathBuf,
+    fd: Option<OwnedFd>,
+}
+
+impl Source {
+    fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            fd: None,
+        }
+    }
+
+    fn open(&mut self) -> Result<(), nix::Error> {
+        self.fd = Some(open_path(&self.path)?);
+        Ok(())
+    }
+
+    /// The descriptor of the source, which must be opened already.
+    fn fd(&self) -> Result<&OwnedFd, nix::Error> {
+        self.fd.as_ref().ok_or(nix::Error::EBADF)
+    }
+}
+
+/// A `/dev` with only [`DEV_NODES`] bound from the host and
+/// [`DEV_SYMLINKS`] in it.
+pub(crate) struct DevPlan {
+    dir: PathBuf,
+    nodes: Vec<(PathBuf, Source)>,
+    symlinks: Vec<(PathBuf, &'static str)>,
+}
+
+impl DevPlan {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        Self {
+            nodes: DEV_NODES
+                .into_iter()
+                .map(|name| (dir.join(name), Source::new(format!("/dev/{}", name))))
+                .collect(),
+            symlinks: DEV_SYMLINKS
+                .into_iter()
+                .map(|(name, link)| (dir.join(name), link))
+                .collect(),
+            dir,
+        }
+    }
+
+    /// Open [`DEV_NODES`] of the host before its `/dev` is masked.
+    pub(crate) fn open(&mut self) -> Result<(), nix::Error> {
+        self.nodes.iter_mut().try_for_each(|(_, node)| node.open())
+    }
+
+    /// Mount a read-only `/dev` as planned.
+    pub(crate) fn mount(&self) -> Result<(), nix::Error> {
+        mount::mount(
+            Some("tmpfs"),
+            &self.dir,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NOEXEC,
+            Some("size=64k,mode=755"),
+        )?;
+
+        // Nodes created on a tmpfs of a user namespace cannot be opened, so
+        // the nodes of the host are bound instead.
+        for (target, node) in &self.nodes {
+            fs::File::create(target).map_err(to_errno)?;
+            bind(node.fd()?, target)?;
+        }
+        for (target, link) in &self.symlinks {
+            unix::fs::symlink(link, target).map_err(to_errno)?;
+        }
+
+        remount_read_only(&self.dir)
+    }
+}
+
+/// Mount a procfs of the pid namespace of runner at `proc_dir`. Processes
+/// of other users are hidden as well, in case the namespace is shared.
+pub(crate) fn mount_proc(proc_dir: &Path) -> Result<(), nix::Error> {
+    mount::mount(
+        Some("proc"),
+        proc_dir,
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        Some("hidepid=2"),
+    )
+}
+
+/// A mount of the spec with its target resolved under the root it is
+/// applied to.
+pub(crate) struct PlannedMount {
+    target: PathBuf,
+    kind: PlannedKind,
+}
+
+enum PlannedKind {
+    Bind {
+        source: Source,
+        read_only: bool,
+    },
+    /// Options of the tmpfs, e.g. its size.
+    Tmpfs {
+        data: String,
+    },
+}
+
+/// Plan `mounts` under `root`, checking that every target is absolute.
+pub(crate) fn plan_mounts<'a>(
+    mounts: impl IntoIterator<Item = &'a Mount>,
+    root: &Path,
+) -> Result<Vec<PlannedMount>, nix::Error> {
+    mounts
+        .into_iter()
+        .map(|mount| {
+            if !is_confined(Path::new(&mount.target)) {
+                return Err(nix::Error::EINVAL);
+            }
+            let kind = match &mount.kind {
+                MountKind::Bind { source, read_only } => PlannedKind::Bind {
+                    source: Source::new(source),
+                    read_only: *read_only,
+                },
+                MountKind::Tmpfs { size } => PlannedKind::Tmpfs {
+                    data: format!("size={},mode=1777", size),
+                },
+            };
+            Ok(PlannedMount {
+                target: root.join(mount.target.trim_start_matches('/')),
+                kind,
+            })
+        })
+        .collect()
+}
+
+/// Open the sources of `mounts` before the host root is masked.
+pub(crate) fn open_mounts(mounts: &mut [PlannedMount]) -> Result<(), nix::Error> {
+    for mount in mounts {
+        if let PlannedKind::Bind { source, .. } = &mut mount.kind {
+            source.open()?;
+        }
+    }
+    Ok(())
+}
+
+/// Mount `mounts` in order. The mount points must exist already.
+pub(crate) fn apply_mounts(mounts: &[PlannedMount]) -> Result<(), nix::Error> {
+    for PlannedMount { target, kind } in mounts {
+        match kind {
+            PlannedKind::Bind { source, read_only } => {
+                // A bind inherits the mode of its source, which may be the
+                // host root already made read-only, so it is always set.
+                bind(source.fd()?, target)?;
+                remount_bind(target, *read_only)?;
+            }
+            PlannedKind::Tmpfs { data } => mount::mount(
+                Some("tmpfs"),
+                target,
+                Some("tmpfs"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+                Some(data.as_str()),
+            )?,
+        }
+    }
+    Ok(())
+}
+
+/// A fresh root with only [`ROOT_ALLOW_LIST`], [`ETC_ALLOW_LIST`], a
+/// minimal `/dev`, a new procfs and the mounts of the spec.
+pub(crate) struct MinimalRoot {
+    entries: Vec<(PathBuf, Entry)>,
+}
+
+/// What to recreate in the minimal root for an allowed host path.
+enum Entry {
+    Symlink(PathBuf),
+    Dir(Source),
+    File(Source),
+}
+
+impl MinimalRoot {
+    /// Plan the entries of the minimal root after those of the host.
+    pub(crate) fn plan() -> Result<Self, nix::Error> {
+        let etc_paths = list_allowed_etc();
+        let mut entries = Vec::new();
+        for path in ROOT_ALLOW_LIST
+            .into_iter()
+            .chain(etc_paths.iter().map(String::as_str))
+        {
+            let entry = match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_symlink() => {
+                    Entry::Symlink(fs::read_link(path).map_err(to_errno)?)
+                }
+                Ok(metadata) if metadata.is_dir() => Entry::Dir(Source::new(path)),
+                Ok(_) => Entry::File(Source::new(path)),
+                Err(_) => continue,
+            };
+            entries.push((get_new_root_path(path), entry));
+        }
+        Ok(Self { entries })
+    }
+
+    /// Build the minimal root with `mounts`, `dev` and a procfs at
+    /// `proc_dir`, all planned under [`NEW_ROOT`], and make it the root of
+    /// runner. Nothing else of the host is reachable afterwards.
+    pub(crate) fn pivot(
+        &mut self,
+        mounts: &[PlannedMount],
+        dev: &DevPlan,
+        proc_dir: &Path,
+    ) -> Result<(), nix::Error> {
+        // Hold every source before covering `NEW_ROOT`, as it may contain them.
+        for (_, entry) in &mut self.entries {
+            if let Entry::Dir(source) | Entry::File(source) = entry {
+                source.open()?;
+            }
+        }
+
+        mount::mount(
+            Some("tmpfs"),
+            NEW_ROOT,
+            Some("tmpfs"),
+            MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Some("size=1m,mode=755"),
+        )?;
+
+        for (target, entry) in &self.entries {
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).map_err(to_errno)?;
+            }
+            match entry {
+                Entry::Symlink(link) => {
+                    unix::fs::symlink(link, target).map_err(to_errno)?;
+                }
+                Entry::Dir(source) => {
+                    fs::create_dir(target).map_err(to_errno)?;
+                    bind(source.fd()?, target)?;
+                    remount_read_only(target)?;
+                }
+                Entry::File(source) => {
+                    fs::File::create(target).map_err(to_errno)?;
+                    bind(source.fd()?, target)?;
+                    remount_read_only(target)?;
+                }
+            }
+        }
+
+        // Create mount points while the new root is still writable.
+        for PlannedMount { target, kind } in mounts {
+            let is_file = match kind {
+                PlannedKind::Bind { source, .. } => {
+                    !SFlag::from_bits_truncate(stat::fstat(source.fd()?)?.st_mode)
+                        .contains(SFlag::S_IFDIR)
+                }
+                PlannedKind::Tmpfs { .. } => false,
+            };
+            if is_file {
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent).map_err(to_errno)?;
+                }
+                fs::File::create(target).map_err(to_errno)?;
+            } else {
+                fs::create_dir_all(target).map_err(to_errno)?;
+            }
+        }
+
+        fs::create_dir_all(&dev.dir).map_err(to_errno)?;
+        dev.mount()?;
+
+        // Mount procfs of the new pid namespace, not that of the host.
+        fs::create_dir_all(proc_dir).map_err(to_errno)?;
+        mount_proc(proc_dir)?;
+
+        // Sources can only be bound while their mounts are still attached.
+        apply_mounts(mounts)?;
+
+        // Stack the old root under the new one and detach it right away.
+        unistd::chdir(NEW_ROOT)?;
+        unistd::pivot_root(".", ".")?;
+        mount::umount2(".", MntFlags::MNT_DETACH)?;
+        unistd::chdir("/")?;
+
+        remount_read_only(Path::new("/"))
+    }
+}
+
+/// Paths of the entries of `/etc` of the host in [`ETC_ALLOW_LIST`].
+fn list_allowed_etc() -> Vec<String> {
+    let Ok(entries) = fs::read_dir("/etc") else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| is_allowed_in_etc(name))
+        .map(|name| format!("/etc/{}", name))
+        .collect()
+}
+
+pub(crate) fn is_allowed_in_etc(name: &str) -> bool {
+    ETC_ALLOW_LIST
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == *pattern,
+        })
+}
+
+/// Check that `target` is absolute and made of plain names only, so that
+/// it never leads out of the root it is joined to (e.g. by `..`).
+fn is_confined(target: &Path) -> bool {
+    let mut components = target.components();
+    components.next() == Some(Component::RootDir)
+        && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+fn get_new_root_path(path: &str) -> PathBuf {
+    Path::new(NEW_ROOT).join(path.trim_start_matches('/'))
+}
+
+fn open_path(path: &Path) -> Result<OwnedFd, nix::Error> {
+    fcntl::open(path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty())
+}
+
+/// Bind the file or directory held by `source` onto `target`. The path of
+/// `source` is formatted on the stack, as the runner must not allocate.
+fn bind(source: &OwnedFd, target: &Path) -> Result<(), nix::Error> {
+    let mut path = [0u8; 32];
+    let mut cursor = &mut path[..];
+    write!(cursor, "/proc/self/fd/{}", source.as_raw_fd()).map_err(to_errno)?;
+    let remaining = cursor.len();
+    let len = path.len() - remaining;
+    mount::mount(
+        Some(OsStr::from_bytes(&path[..len])),
+        target,
+        None::<&str>,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None::<&str>,
+    )
+}
+
+/// Remount a bind mount read-only.
+fn remount_read_only(target: &Path) -> Result<(), nix::Error> {
+    remount_bind(target, true)
+}
+
+/// Remount a bind mount read-only or writable. Flags the kernel locked for
+/// mounts from a more privileged namespace are kept, or the remount is
+/// refused.
+fn remount_bind(target: &Path, read_only: bool) -> Result<(), nix::Error> {
+    const LOCKED_FLAGS: [(FsFlags, MsFlags); 6] = [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ];
+
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT;
+    flags.set(MsFlags::MS_RDONLY, read_only);
+
+    let current = statvfs::statvfs(target)?.flags();
+    let flags = LOCKED_FLAGS
+        .into_iter()
+        .filter(|(fs_flag, _)| current.contains(*fs_flag))
+        .fold(flags, |flags, (_, ms_flag)| flags | ms_flag);
+
+    mount::mount(None::<&str>, target, None::<&str>, flags, None::<&str>)
+}
+
+fn to_errno(e: io::Error) -> nix::Error {
+    nix::Error::from_raw(e.raw_os_error().unwrap_or(libc::EIO))
+}
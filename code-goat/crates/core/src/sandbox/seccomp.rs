@@ -1,12 +1,35 @@
-use std::ffi::CString;
+use std::{
+    ffi::CString,
+    fmt::{self, Display},
+    io::{self, Read},
+    iter, mem,
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+};
 
 use libseccomp::{
-    ScmpAction, ScmpArgCompare, ScmpFilterContext, ScmpSyscall, error::SeccompError, scmp_cmp,
+    ScmpAction, ScmpArgCompare, ScmpFilterContext, ScmpNotifReq, ScmpNotifResp, ScmpNotifRespFlags,
+    ScmpSyscall, error::SeccompError, scmp_cmp,
+};
+use log::{error, info, warn};
+use nix::{
+    errno::Errno,
+    libc,
+    poll::{self, PollFd, PollFlags},
+    sys::signal::{self, Signal},
+    unistd::Pid,
 };
-use log::warn;
+use serde::{Deserialize, Serialize};
 use strum_macros::{Display, EnumString};
 
-#[derive(Debug, PartialEq, EnumString, Display)]
+use crate::models::InternalError;
+
+#[derive(Debug, Clone, Copy, PartialEq, EnumString, Display, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ScmpPolicy {
     #[strum(ascii_case_insensitive)]
     Unsafe,
@@ -14,16 +37,39 @@ pub enum ScmpPolicy {
     Strict,
     #[strum(ascii_case_insensitive)]
     Python,
-    // Java,
+    #[strum(ascii_case_insensitive)]
+    Compile,
+    #[strum(ascii_case_insensitive)]
+    Java,
+    /// Native programs whose runtime starts threads (e.g. Go and Rust).
+    #[strum(ascii_case_insensitive)]
+    Threaded,
 }
 
+/// Minimum API level of libseccomp to report syscalls to a listener.
+const NOTIFY_API_LEVEL: u32 = 6;
+
 /// Apply seccomp whitelist.
-/// TODO: more comment
-pub(crate) fn apply_filter(
+/// A seccomp filter compiled by the judger, which the runner loads without
+/// calling into libseccomp.
+pub(crate) struct ScmpProgram {
+    filter: Vec<libc::sock_filter>,
+    /// Whether syscalls not whitelisted are reported to a listener.
+    is_notified: bool,
+}
+
+/// Compile the filter of `scmp_policy`, or none if it permits everything.
+/// Syscalls not whitelisted are reported to a listener instead of being
+/// executed.
+///
+/// If libseccomp or the kernel is too old to report syscalls, the process
+/// is killed by `SIGSYS` on a syscall not whitelisted instead.
+pub(crate) fn compile_filter(
     scmp_policy: &ScmpPolicy,
     exe_path: &CString,
-) -> Result<(), SeccompError> {
-    let whitelist = get_whitelist(scmp_policy, exe_path)?;
+) -> Result<Option<ScmpProgram>, InternalError> {
+    let to_error = |e: &dyn Display| InternalError::CompileSeccomp(e.to_string());
+    let whitelist = get_whitelist(scmp_policy, exe_path).map_err(|e| to_error(&e))?;
     if whitelist.len() == 0 {
         warn!(
             "Running under an UNSAFE seccomp policy!
@@ -31,24 +77,207 @@ pub(crate) fn apply_filter(
     This should ONLY be used for debugging or testing trusted code.",
             scmp_policy
         );
-        return Ok(());
+        return Ok(None);
     };
 
-    // Kill process if the runner provokes any syscall not whitelisted.
-    let mut filter = ScmpFilterContext::new(ScmpAction::KillProcess)?;
+    // Notify the listener if the runner provokes any syscall not whitelisted.
+    let is_notified = libseccomp::get_api() >= NOTIFY_API_LEVEL;
+    let default_action = if is_notified {
+        ScmpAction::Notify
+    } else {
+        warn!("Seccomp notification is not supported; killing violators instead.");
+        ScmpAction::KillProcess
+    };
+    let mut filter = ScmpFilterContext::new(default_action).map_err(|e| to_error(&e))?;
 
     for rule in whitelist {
         match rule.comparator {
-            Some(cmp) => filter.add_rule_conditional(ScmpAction::Allow, rule.syscall, &[cmp]),
-            None => filter.add_rule(ScmpAction::Allow, rule.syscall),
-        }?;
+            Some(cmp) => filter.add_rule_conditional(rule.action, rule.syscall, &[cmp]),
+            None => filter.add_rule(rule.action, rule.syscall),
+        }
+        .map_err(|e| to_error(&e))?;
+    }
+
+    // A program has at most `BPF_MAXINSNS` instructions, which always fit
+    // in the buffer of a pipe.
+    let (mut reader, writer) = io::pipe()?;
+    filter.export_bpf(&writer).map_err(|e| to_error(&e))?;
+    drop(writer);
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    let filter = bytes
+        .chunks_exact(mem::size_of::<libc::sock_filter>())
+        .map(|chunk| libc::sock_filter {
+            code: u16::from_ne_bytes([chunk[0], chunk[1]]),
+            jt: chunk[2],
+            jf: chunk[3],
+            k: u32::from_ne_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+        })
+        .collect();
+    Ok(Some(ScmpProgram {
+        filter,
+        is_notified,
+    }))
+}
+
+impl ScmpProgram {
+    /// Load the filter into the calling process, which must have set
+    /// `no_new_privs`. Returns the fd of the listener, which the judger
+    /// should take over with [`ScmpMonitor`] before the untrusted code runs.
+    pub(crate) fn load(&self) -> Result<Option<RawFd>, nix::Error> {
+        let program = libc::sock_fprog {
+            len: self.filter.len() as libc::c_ushort,
+            filter: self.filter.as_ptr() as *mut libc::sock_filter,
+        };
+        let flags = if self.is_notified {
+            libc::SECCOMP_FILTER_FLAG_NEW_LISTENER
+        } else {
+            0
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                flags,
+                &program as *const libc::sock_fprog,
+            )
+        };
+        let notify_fd = Errno::result(res)?;
+        Ok(self.is_notified.then_some(notify_fd as RawFd))
+    }
+}
+
+/// A syscall that the runner provoked against its seccomp policy.
+#[derive(Debug)]
+pub(crate) struct ScmpViolation {
+    syscall: String,
+    args: [u64; 6],
+}
+
+impl Display for ScmpViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| format!("{:#x}", arg))
+            .collect::<Vec<String>>()
+            .join(", ");
+        write!(f, "Blocked system call: {}({})", self.syscall, args)
+    }
+}
+
+/// Watches the seccomp listener of a runner and kills the runner as soon
+/// as it provokes a syscall that is not whitelisted.
+pub(crate) struct ScmpMonitor {
+    handle: Option<JoinHandle<Option<ScmpViolation>>>,
+    is_stopped: Arc<AtomicBool>,
+}
+
+impl ScmpMonitor {
+    /// Interval to check whether the monitor should stop.
+    const POLL_TIMEOUT_MS: u8 = 100;
+
+    /// Take over the listener `notify_fd` from the runner `runner_pid`.
+    pub(crate) fn new(runner_pid: Pid, notify_fd: RawFd) -> Result<Self, nix::Error> {
+        let notify_fd = get_fd_of(runner_pid, notify_fd)?;
+        let is_stopped = Arc::new(AtomicBool::new(false));
+
+        let handle = {
+            let is_stopped = Arc::clone(&is_stopped);
+            thread::spawn(move || Self::watch(runner_pid, notify_fd, &is_stopped))
+        };
+
+        Ok(Self {
+            handle: Some(handle),
+            is_stopped,
+        })
     }
-    filter.load()
+
+    fn watch(
+        runner_pid: Pid,
+        notify_fd: OwnedFd,
+        is_stopped: &AtomicBool,
+    ) -> Option<ScmpViolation> {
+        while !is_stopped.load(Ordering::Relaxed) {
+            let mut fds = [PollFd::new(notify_fd.as_fd(), PollFlags::POLLIN)];
+            match poll::poll(&mut fds, Self::POLL_TIMEOUT_MS) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    error!("Failed to poll seccomp listener: {}", e);
+                    return None;
+                }
+            }
+            // Every process under the filter has exited.
+            if fds[0]
+                .revents()
+                .is_some_and(|revents| revents.contains(PollFlags::POLLHUP))
+            {
+                return None;
+            }
+
+            let request = match ScmpNotifReq::receive(notify_fd.as_raw_fd()) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!("Failed to receive seccomp notification: {}", e);
+                    continue;
+                }
+            };
+
+            // Kill the runner before responding, so that the syscall is
+            // never executed even if the response fails.
+            let _ = signal::kill(runner_pid, Signal::SIGKILL);
+            let response =
+                ScmpNotifResp::new_error(request.id, -libc::EPERM, ScmpNotifRespFlags::empty());
+            let _ = response.respond(notify_fd.as_raw_fd());
+
+            let syscall = request
+                .data
+                .syscall
+                .get_name()
+                .unwrap_or_else(|_| format!("{:?}", request.data.syscall));
+            info!("Killed runner provoking a blocked system call {}.", syscall);
+
+            return Some(ScmpViolation {
+                syscall,
+                args: request.data.args,
+            });
+        }
+        None
+    }
+
+    /// Stop watching and return the violation, if any.
+    pub(crate) fn finish(mut self) -> Option<ScmpViolation> {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        self.handle
+            .take()
+            .and_then(|handle| handle.join().ok().flatten())
+    }
+}
+
+impl Drop for ScmpMonitor {
+    fn drop(&mut self) {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Duplicate the file descriptor `fd` of process `pid` into the judger.
+fn get_fd_of(pid: Pid, fd: RawFd) -> Result<OwnedFd, nix::Error> {
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid.as_raw(), 0) };
+    let pidfd = unsafe { OwnedFd::from_raw_fd(Errno::result(pidfd)? as RawFd) };
+
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) };
+    Ok(unsafe { OwnedFd::from_raw_fd(Errno::result(fd)? as RawFd) })
 }
 
 struct ScmpRule {
     syscall: ScmpSyscall,
     comparator: Option<ScmpArgCompare>,
+    action: ScmpAction,
 }
 
 impl ScmpRule {
@@ -57,6 +286,24 @@ impl ScmpRule {
         Ok(ScmpRule {
             syscall,
             comparator: None,
+            action: ScmpAction::Allow,
+        })
+    }
+
+    /// Fail the syscall with `errno` instead of reporting it as a violation,
+    /// for runtimes that fall back to another syscall.
+    fn fail_with(name: &str, errno: i32) -> Result<Self, SeccompError> {
+        Ok(ScmpRule {
+            action: ScmpAction::Errno(errno),
+            ..Self::new(name)?
+        })
+    }
+
+    /// Fail the syscall with `errno` only if its arguments match `cmp`.
+    fn fail_with_cond(name: &str, cmp: ScmpArgCompare, errno: i32) -> Result<Self, SeccompError> {
+        Ok(ScmpRule {
+            comparator: Some(cmp),
+            ..Self::fail_with(name, errno)?
         })
     }
 }
@@ -71,7 +318,9 @@ fn get_whitelist(
         // Allow forbidden syscalls only for the initial execution.
         .chain(EXEC_SYSCALLS.into_cond_rules(Some(scmp_cmp!($arg0 == exe_path_addr))))
         // Allow file access only for read operation.
-        .chain(FILE_SYSCALLS.into_cond_rules(Some(scmp_cmp!($arg1 & WRITE_FLAGS == 0))))
+        // Flags are the 2nd argument of `open` but the 3rd one of `openat`.
+        .chain(["open"].into_cond_rules(Some(scmp_cmp!($arg1 & WRITE_FLAGS == 0))))
+        .chain(["openat"].into_cond_rules(Some(scmp_cmp!($arg2 & WRITE_FLAGS == 0))))
         // Disable changing resource limits except getting them.
         // todo: arg0 == 0 자기 것만 확인하게 하기 (괜찮나?)
         .chain(["prlimit64"].into_cond_rules(Some(scmp_cmp!($arg2 == 0))));
@@ -80,6 +329,40 @@ fn get_whitelist(
         ScmpPolicy::Unsafe => Ok(vec![]),
         ScmpPolicy::Strict => common_rules.collect(),
         ScmpPolicy::Python => common_rules.chain(PYTHON_SYSCALLS.into_rules()).collect(),
+        // Compilers run a pipeline of trusted tools (e.g. cc1, as, ld) that
+        // write intermediate files, so neither exec nor write is restricted.
+        ScmpPolicy::Compile => COMMON_SYSCALLS
+            .into_rules()
+            .chain(EXEC_SYSCALLS.into_rules())
+            .chain(FILE_SYSCALLS.into_rules())
+            .chain(COMPILE_SYSCALLS.into_rules())
+            .collect(),
+        ScmpPolicy::Java => common_rules
+            .chain(JAVA_SYSCALLS.into_rules())
+            // Allow creating threads but not processes.
+            .chain(
+                THREAD_SYSCALLS
+                    .into_cond_rules(Some(scmp_cmp!($arg0 & CLONE_THREAD == CLONE_THREAD))),
+            )
+            // Arguments of `clone3` cannot be inspected, so make glibc
+            // fall back to `clone`.
+            .chain(iter::once(ScmpRule::fail_with("clone3", libc::ENOSYS)))
+            .collect(),
+        ScmpPolicy::Threaded => common_rules
+            .chain(THREADED_SYSCALLS.into_rules())
+            .chain(
+                THREAD_SYSCALLS
+                    .into_cond_rules(Some(scmp_cmp!($arg0 & CLONE_THREAD == CLONE_THREAD))),
+            )
+            .chain(iter::once(ScmpRule::fail_with("clone3", libc::ENOSYS)))
+            // The Go runtime raises its limit of open files on start, and
+            // goes on if it is not allowed to.
+            .chain(iter::once(ScmpRule::fail_with_cond(
+                "prlimit64",
+                scmp_cmp!($arg2 != 0),
+                libc::EPERM,
+            )))
+            .collect(),
     }
 }
 
@@ -105,6 +388,7 @@ impl SyscallList for [&str] {
             Ok(ScmpRule {
                 syscall,
                 comparator: cmp,
+                action: ScmpAction::Allow,
             })
         })
     }
@@ -139,8 +423,10 @@ const COMMON_SYSCALLS: [&str; 23] = [
 // TODO: consider adding these syscalls as well
 // const GENERAL_SYSCALLS: [&str; 3] = ["access", "arch_prctl", "clock_gettime"];
 
-const PYTHON_SYSCALLS: [&str; 12] = [
-    "fcntl", // Manipulate file descriptor
+const PYTHON_SYSCALLS: [&str; 15] = [
+    "access",     // Check user's permissions for a file
+    "arch_prctl", // Set architecture-specific thread state
+    "fcntl",      // Manipulate file descriptor
     // "getcwd",       // Get current working directory
     "getdents64",   // Get directory entries
     "getegid",      // Get effective user group ID
@@ -151,6 +437,7 @@ const PYTHON_SYSCALLS: [&str; 12] = [
     "ioctl",        // Control device I/O
     "mremap",       // Resize memory mapping
     "rt_sigaction", // Register a signal handler
+    "sysinfo",      // Get system statistics (e.g. total memory)
     //
     "socket",  // Create a socket
     "connect", // Connect a socket
@@ -162,6 +449,121 @@ const PYTHON_SYSCALLS: [&str; 12] = [
 // [pid 65108] close(3)                    = 0
 // [pid 65108] socket(AF_UNIX, SOCK_STREAM|SOCK_CLOEXEC|SOCK_NONBLOCK, 0) = 3
 
+const COMPILE_SYSCALLS: [&str; 44] = [
+    "access",            // Check user's permissions for a file
+    "arch_prctl",        // Set architecture-specific thread state
+    "chmod",             // Change permissions of a file
+    "clock_gettime",     // Get time of a clock
+    "clone",             // Create a child process (e.g. cc1, as, ld)
+    "clone3",            // Create a child process with extended arguments
+    "dup",               // Duplicate a fd
+    "dup2",              // Duplicate a fd to a specified fd
+    "dup3",              // Duplicate a fd with flags
+    "fchmod",            // Change permissions of an open file
+    "fcntl",             // Manipulate file descriptor
+    "ftruncate",         // Truncate an open file
+    "getcwd",            // Get current working directory
+    "getdents64",        // Get directory entries
+    "getegid",           // Get effective user group ID
+    "geteuid",           // Get effective user ID
+    "getgid",            // Get user group ID
+    "getpid",            // Get process ID
+    "getppid",           // Get parent process ID
+    "gettid",            // Get thread ID
+    "getuid",            // Get user ID
+    "ioctl",             // Control device I/O
+    "lstat",             // Get status of a file without following links
+    "madvise",           // Give advice about use of memory
+    "mkdir",             // Create a directory
+    "mremap",            // Resize memory mapping
+    "pipe",              // Create a pipe between the tools
+    "pipe2",             // Create a pipe with flags
+    "prlimit64",         // Get and set resource limits
+    "rename",            // Rename a file
+    "rmdir",             // Remove a directory
+    "rt_sigaction",      // Register a signal handler
+    "rt_sigprocmask",    // Change blocked signals
+    "rt_sigreturn",      // Return from a signal handler
+    "sched_getaffinity", // Get CPUs a thread may run on
+    "stat",              // Get status of a file
+    "statx",             // Get extended status of a file
+    "sysinfo",           // Get system statistics
+    "umask",             // Set file mode creation mask
+    "uname",             // Get name of the kernel
+    "unlink",            // Remove a file
+    "unlinkat",          // Remove a file relative to a directory
+    "vfork",             // Create a child process sharing memory
+    "wait4",             // Wait for a child process
+];
+
+const JAVA_SYSCALLS: [&str; 33] = [
+    "access",            // Check user's permissions for a file
+    "arch_prctl",        // Set architecture-specific thread state
+    "clock_getres",      // Get resolution of a clock
+    "clock_gettime",     // Get time of a clock
+    "clock_nanosleep",   // Sleep with a specified clock
+    "fcntl",             // Manipulate file descriptor
+    "getcwd",            // Get current working directory
+    "getdents64",        // Get directory entries
+    "getegid",           // Get effective user group ID
+    "geteuid",           // Get effective user ID
+    "getgid",            // Get user group ID
+    "getpid",            // Get process ID
+    "getrusage",         // Get resource usage of threads
+    "gettid",            // Get thread ID
+    "getuid",            // Get user ID
+    "ioctl",             // Control device I/O
+    "lstat",             // Get status of a file without following links
+    "madvise",           // Give advice about use of memory (e.g. by GC)
+    "membarrier",        // Issue memory barriers on all threads
+    "mremap",            // Resize memory mapping
+    "nanosleep",         // Sleep for a specified time
+    "prctl",             // Name threads
+    "rt_sigaction",      // Register a signal handler
+    "rt_sigprocmask",    // Change blocked signals
+    "rt_sigreturn",      // Return from a signal handler
+    "sched_getaffinity", // Get CPUs a thread may run on
+    "sched_yield",       // Yield the processor
+    "sigaltstack",       // Set alternate signal stack
+    "stat",              // Get status of a file
+    "statx",             // Get extended status of a file
+    "sysinfo",           // Get system statistics (e.g. physical memory)
+    "tgkill",            // Send a signal to a thread (e.g. for safepoints)
+    "uname",             // Get name of the kernel
+];
+
+const THREADED_SYSCALLS: [&str; 26] = [
+    "access",            // Check user's permissions for a file
+    "arch_prctl",        // Set architecture-specific thread state
+    "clock_gettime",     // Get time of a clock
+    "clock_nanosleep",   // Sleep with a specified clock
+    "epoll_create1",     // Create an epoll instance (e.g. Go netpoller)
+    "epoll_ctl",         // Register a fd to an epoll instance
+    "epoll_pwait",       // Wait for events of an epoll instance
+    "fcntl",             // Manipulate file descriptor
+    "getpid",            // Get process ID
+    "getrusage",         // Get resource usage of threads
+    "gettid",            // Get thread ID
+    "madvise",           // Give advice about use of memory (e.g. by GC)
+    "mincore",           // Check whether pages are resident in memory
+    "nanosleep",         // Sleep for a specified time
+    "pipe2",             // Create a pipe with flags (e.g. to wake netpoller)
+    "poll",              // Wait for events of fds (e.g. Rust checking stdio)
+    "prctl",             // Name threads
+    "rt_sigaction",      // Register a signal handler
+    "rt_sigprocmask",    // Change blocked signals
+    "rt_sigreturn",      // Return from a signal handler
+    "sched_getaffinity", // Get CPUs a thread may run on
+    "sched_yield",       // Yield the processor
+    "sigaltstack",       // Set alternate signal stack
+    "statx",             // Get extended status of a file
+    "tgkill",            // Send a signal to a thread (e.g. Go preemption)
+    "uname",             // Get name of the kernel
+];
+
+const THREAD_SYSCALLS: [&str; 1] = ["clone"];
+const CLONE_THREAD: u64 = nix::libc::CLONE_THREAD as u64;
+
 const EXEC_SYSCALLS: [&str; 1] = ["execve"];
 
 const FILE_SYSCALLS: [&str; 2] = ["open", "openat"];
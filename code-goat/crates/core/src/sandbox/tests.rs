@@ -2,16 +2,18 @@ use super::*;
 
 mod mount_sandbox {
     use std::{
+        env,
         fs::{self, File},
         io::{self, Read, Write},
-        panic::{self, UnwindSafe},
+        panic::{self, AssertUnwindSafe, UnwindSafe},
+        process,
         time::{SystemTime, UNIX_EPOCH},
     };
 
     use nix::{
         sched::{self, CloneFlags},
         sys::wait::{self, WaitStatus},
-        unistd::ForkResult,
+        unistd::{ForkResult, Gid, Uid},
     };
 
     use super::*;
@@ -22,7 +24,15 @@ mod mount_sandbox {
         Write,
     }
 
-    fn test_mount<F, R>(test_function: F)
+    /// Directory bound into the sandbox by a test. It is created outside of
+    /// [`SENSITIVE_DIRS`], under which a masked root has no mount points.
+    fn get_workspace(name: &str) -> String {
+        let dir = env::temp_dir().join(format!("code-goat-{}-{}", name, process::id()));
+        fs::create_dir_all(&dir).expect("Failed to create workspace");
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn test_mount<F, R>(root: RootMode, mounts: Vec<Mount>, test_function: F)
     where
         F: FnOnce() -> R + UnwindSafe,
     {
@@ -52,7 +62,16 @@ mod mount_sandbox {
             Ok(ForkResult::Child) => {
                 drop(consumer);
 
-                let result = panic::catch_unwind(|| {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    // Only the ids of the test itself can be mapped from
+                    // inside the namespace.
+                    let id_map = user::IdMap {
+                        host_uid: Uid::effective().as_raw(),
+                        host_gid: Gid::effective().as_raw(),
+                        sandbox_uid: user::SANDBOX_ID,
+                        sandbox_gid: user::SANDBOX_ID,
+                    };
+
                     // Create new mount namespace and mount the sandbox.
                     sched::unshare(
                         CloneFlags::CLONE_NEWUSER
@@ -60,21 +79,35 @@ mod mount_sandbox {
                             | CloneFlags::CLONE_NEWPID,
                     )
                     .expect("Failed to unshare mount namespace");
-                    mount_sandbox().expect("Failed to mount sandbox");
-                    test_function(); // Run the test function after mounting.
-                });
+                    user::write_id_map(Pid::this(), &id_map).expect("Failed to write id map");
+                    user::switch_user(&id_map).expect("Failed to switch user");
 
-                if let Err(e) = result {
-                    if let Some(msg) = e.downcast_ref::<String>() {
-                        producer.write(msg.as_bytes()).expect("Failed to write");
-                    } else if let Some(msg) = e.downcast_ref::<&'static str>() {
-                        producer.write(msg.as_bytes()).expect("Failed to write");
-                    } else {
-                        producer
-                            .write(b"Unknown type of error occurred.")
-                            .expect("Failed to write");
+                    // Only children join the new pid namespace, which a new
+                    // procfs can be mounted for.
+                    match unsafe { unistd::fork() }.expect("Failed to fork process") {
+                        ForkResult::Parent { child } => {
+                            let status = wait::waitpid(child, None);
+                            assert!(
+                                matches!(status, Ok(WaitStatus::Exited(_, 0))),
+                                "Unexpected wait status: {:?}",
+                                status
+                            );
+                        }
+                        ForkResult::Child => {
+                            let result = panic::catch_unwind(|| {
+                                let workdir = mounts.first().map(|mount| mount.target.clone());
+                                plan_sandbox(root, &mounts, None, workdir.as_deref())
+                                    .and_then(|mut plan| mount_sandbox(&mut plan))
+                                    .expect("Failed to mount sandbox");
+                                drop_capabilities().expect("Failed to drop capabilities");
+                                test_function(); // Run the test function after mounting.
+                            });
+                            report(&mut producer, result);
+                            unsafe { libc::_exit(0) };
+                        }
                     }
-                }
+                }));
+                report(&mut producer, result);
             }
             Err(e) => {
                 panic!("Failed to fork process: {}", e);
@@ -82,6 +115,48 @@ mod mount_sandbox {
         }
     }
 
+    /// Drop every capability held in the user namespace, as `execve` does
+    /// for the untrusted code.
+    fn drop_capabilities() -> Result<(), nix::Error> {
+        const CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+        #[repr(C)]
+        struct CapHeader {
+            version: u32,
+            pid: libc::c_int,
+        }
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct CapData {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        let header = CapHeader {
+            version: CAPABILITY_VERSION_3,
+            pid: 0,
+        };
+        let data = [CapData::default(); 2];
+        Errno::result(unsafe { libc::syscall(libc::SYS_capset, &header, data.as_ptr()) }).map(drop)
+    }
+
+    /// Send the message of a panic in a child to the test process.
+    fn report(producer: &mut io::PipeWriter, result: thread::Result<()>) {
+        if let Err(e) = result {
+            if let Some(msg) = e.downcast_ref::<String>() {
+                producer.write(msg.as_bytes()).expect("Failed to write");
+            } else if let Some(msg) = e.downcast_ref::<&'static str>() {
+                producer.write(msg.as_bytes()).expect("Failed to write");
+            } else {
+                producer
+                    .write(b"Unknown type of error occurred.")
+                    .expect("Failed to write");
+            }
+        }
+    }
+
     fn has_permission_to(action: Action, dir_path: &Path) -> bool {
         match action {
             Action::Read => fs::read_dir(dir_path).and(Ok(true)),
@@ -111,14 +186,12 @@ mod mount_sandbox {
 
     #[test]
     fn root_is_read_only() {
-        test_mount(|| {
+        test_mount(RootMode::Masked, vec![], || {
             let root_dir = fs::read_dir("/").expect("Failed to read root directory");
-            let workspace = env::var("SANDBOX_WORKSPACE").unwrap_or_default();
             for entry in root_dir {
                 let path = entry.expect("Failed to read directory entry").path();
 
                 if path.is_dir()
-                    && path.to_str() != Some(&workspace)
                     // NOTE: /proc is a special filesystem that allows writing 
                     // to some files even in read-only mount.
                     && path.to_str() != Some("/proc")
@@ -131,21 +204,348 @@ mod mount_sandbox {
 
     #[test]
     fn sensitive_dirs_are_empty() {
-        test_mount(|| {
-            for entry in SENSITIVE_DIRS {
+        test_mount(RootMode::Masked, vec![], || {
+            // `/dev` is masked, then replaced with a minimal one.
+            for entry in SENSITIVE_DIRS.into_iter().filter(|&dir| dir != "/dev") {
                 assert!(!has_permission_to(Action::Read, Path::new(entry)));
             }
         });
     }
 
     #[test]
-    fn workspace_is_writable() {
-        test_mount(|| {
-            if let Ok(workspace) = env::var("SANDBOX_WORKSPACE") {
-                let path = Path::new(&workspace);
-                assert!(has_permission_to(Action::Read, path));
-                assert!(has_permission_to(Action::Write, path));
+    fn dev_has_only_minimal_nodes() {
+        for root in [RootMode::Minimal, RootMode::Masked] {
+            test_mount(root, vec![], || {
+                let mut names: Vec<String> = fs::read_dir("/dev")
+                    .expect("Failed to read /dev")
+                    .map(|entry| entry.expect("Failed to read entry").file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .collect();
+                names.sort();
+                let mut expected: Vec<&str> = rootfs::DEV_NODES
+                    .into_iter()
+                    .chain(rootfs::DEV_SYMLINKS.map(|(name, _)| name))
+                    .collect();
+                expected.sort();
+                assert_eq!(names, expected);
+
+                fs::write("/dev/null", b"discarded").expect("Failed to write /dev/null");
+                let mut random = [0u8; 16];
+                File::open("/dev/urandom")
+                    .and_then(|mut file| file.read_exact(&mut random))
+                    .expect("Failed to read /dev/urandom");
+                assert!(!has_permission_to(Action::Write, Path::new("/dev")));
+            });
+        }
+    }
+
+    #[test]
+    fn proc_shows_only_own_processes() {
+        for root in [RootMode::Minimal, RootMode::Masked] {
+            test_mount(root, vec![], || {
+                let pids: Vec<String> = fs::read_dir("/proc")
+                    .expect("Failed to read /proc")
+                    .map(|entry| entry.expect("Failed to read entry").file_name())
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .filter(|name| name.bytes().all(|byte| byte.is_ascii_digit()))
+                    .collect();
+                assert_eq!(pids, ["1"]);
+            });
+        }
+    }
+
+    #[test]
+    fn minimal_root_has_only_allowed_dirs() {
+        let workspace = get_workspace("minimal");
+        let mounts = vec![Mount::bind(&workspace, &workspace, true)];
+        test_mount(RootMode::Minimal, mounts, || {
+            let workspace_top = Path::new(&workspace)
+                .components()
+                .nth(1)
+                .map(|component| component.as_os_str().to_owned());
+
+            let root_dir = fs::read_dir("/").expect("Failed to read root directory");
+            for entry in root_dir {
+                let name = entry.expect("Failed to read entry").file_name();
+                let is_allowed = name == "proc"
+                    || name == "dev"
+                    || name == "etc"
+                    || Some(&name) == workspace_top.as_ref()
+                    || rootfs::ROOT_ALLOW_LIST
+                        .iter()
+                        .any(|path| Path::new(path).ends_with(&name));
+                assert!(is_allowed, "{:?} leaked into the sandbox", name);
+            }
+            assert!(!has_permission_to(Action::Write, Path::new("/")));
+        });
+        let _ = fs::remove_dir_all(&workspace);
+    }
+
+    #[test]
+    fn minimal_root_has_only_allowed_etc() {
+        let list_etc = || -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir("/etc")
+                .into_iter()
+                .flatten()
+                .map(|entry| entry.expect("Failed to read entry").file_name())
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let mut expected = list_etc();
+        expected.retain(|name| rootfs::is_allowed_in_etc(name));
+
+        test_mount(RootMode::Minimal, vec![], || {
+            assert_eq!(list_etc(), expected);
+            if expected.iter().any(|name| name == "passwd") {
+                fs::read("/etc/passwd").expect("Failed to read /etc/passwd");
             }
         });
     }
+
+    #[test]
+    fn bind_mounts_follow_their_mode() {
+        let workspace = get_workspace("bind");
+        for root in [RootMode::Minimal, RootMode::Masked] {
+            for read_only in [true, false] {
+                let mounts = vec![Mount::bind(&workspace, &workspace, read_only)];
+                test_mount(root, mounts, || {
+                    let path = Path::new(&workspace);
+                    assert!(has_permission_to(Action::Read, path));
+                    assert_eq!(has_permission_to(Action::Write, path), !read_only);
+                });
+            }
+        }
+        let _ = fs::remove_dir_all(&workspace);
+    }
+
+    #[test]
+    fn tmpfs_is_writable_and_working_directory_is_set() {
+        for root in [RootMode::Minimal, RootMode::Masked] {
+            let mounts = vec![Mount::tmpfs("/tmp", 1 << 20)];
+            test_mount(root, mounts, || {
+                assert_eq!(env::current_dir().ok(), Some(PathBuf::from("/tmp")));
+                assert!(has_permission_to(Action::Write, Path::new("/tmp")));
+            });
+        }
+    }
+}
+
+mod mount_targets {
+    use super::*;
+
+    #[test]
+    fn targets_never_leave_the_root() {
+        for target in ["/tmp/../etc", "tmp", "/./tmp/..", "../tmp"] {
+            let mounts = [Mount::tmpfs(target, 1 << 20)];
+            assert_eq!(
+                rootfs::plan_mounts(&mounts, Path::new("/")).err(),
+                Some(nix::Error::EINVAL),
+                "{}",
+                target
+            );
+        }
+
+        let mounts = [Mount::tmpfs("/tmp/./work/", 1 << 20)];
+        assert!(rootfs::plan_mounts(&mounts, Path::new("/")).is_ok());
+    }
+}
+
+mod memory_events {
+    use super::*;
+
+    #[test]
+    fn parse_counters() {
+        let events = "low 0\nhigh 0\nmax 12\noom 1\noom_kill 1\noom_group_kill 0\n";
+        assert_eq!(
+            MemoryEvents::parse(events),
+            MemoryEvents {
+                max: 12,
+                oom: 1,
+                oom_kill: 1,
+            }
+        );
+    }
+}
+
+mod ipc_namespace {
+    use std::process;
+
+    use nix::{
+        sched,
+        sys::wait::{self, WaitStatus},
+        unistd::ForkResult,
+    };
+
+    use super::*;
+
+    /// Check in a runner's namespaces whether a System V shared memory
+    /// segment with `key` is visible.
+    fn is_visible_in_sandbox(namespaces: Namespaces, key: libc::key_t) -> bool {
+        match unsafe { unistd::fork() }.expect("Failed to fork process") {
+            ForkResult::Parent { child } => match wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => {
+                    assert!(code <= 1, "Failed to unshare namespaces");
+                    code == 0
+                }
+                status => panic!("Unexpected wait status: {:?}", status),
+            },
+            ForkResult::Child => {
+                let code = match sched::unshare(get_clone_flags(&namespaces)) {
+                    Ok(_) if unsafe { libc::shmget(key, 0, 0) } >= 0 => 0,
+                    Ok(_) => 1,
+                    Err(_) => 2,
+                };
+                unsafe { libc::_exit(code) }
+            }
+        }
+    }
+
+    #[test]
+    fn host_ipc_is_invisible() {
+        let key = process::id() as libc::key_t;
+        let shm_id = unsafe { libc::shmget(key, 4096, libc::IPC_CREAT | libc::IPC_EXCL | 0o600) };
+        assert!(shm_id >= 0, "Failed to create shared memory on host");
+
+        let is_hidden = !is_visible_in_sandbox(Namespaces::default(), key);
+        let is_shared = is_visible_in_sandbox(
+            Namespaces {
+                ipc: false,
+                ..Namespaces::default()
+            },
+            key,
+        );
+        unsafe { libc::shmctl(shm_id, libc::IPC_RMID, std::ptr::null_mut()) };
+
+        assert!(is_hidden);
+        assert!(is_shared);
+    }
+}
+
+mod network_namespace {
+    use std::net::UdpSocket;
+
+    use nix::{
+        sched,
+        sys::wait::{self, WaitStatus},
+        unistd::ForkResult,
+    };
+
+    use super::*;
+    use crate::models::NetworkMode;
+
+    /// Check in a runner's namespaces, with the network set up for
+    /// `network`, whether `probe` holds.
+    fn holds_in_sandbox(network: NetworkMode, probe: fn() -> bool) -> bool {
+        match unsafe { unistd::fork() }.expect("Failed to fork process") {
+            ForkResult::Parent { child } => match wait::waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => {
+                    assert!(code <= 1, "Failed to set up network namespace");
+                    code == 0
+                }
+                status => panic!("Unexpected wait status: {:?}", status),
+            },
+            ForkResult::Child => {
+                let is_set_up = sched::unshare(get_clone_flags(&Namespaces::default())).is_ok()
+                    && (network == NetworkMode::Isolated || bring_up_loopback().is_ok());
+                let code = match is_set_up {
+                    true if probe() => 0,
+                    true => 1,
+                    false => 2,
+                };
+                unsafe { libc::_exit(code) }
+            }
+        }
+    }
+
+    fn can_reach_outside() -> bool {
+        // An address reserved for documentation, which needs a route only.
+        UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| socket.connect("192.0.2.1:9"))
+            .is_ok()
+    }
+
+    fn is_loopback_up() -> bool {
+        let Ok(socket) = UdpSocket::bind("0.0.0.0:0") else {
+            return false;
+        };
+        let mut request: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        let res = unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS, &mut request) };
+        res >= 0 && unsafe { request.ifr_ifru.ifru_flags } & libc::IFF_UP as libc::c_short != 0
+    }
+
+    #[test]
+    fn isolated_has_no_network() {
+        assert!(!holds_in_sandbox(NetworkMode::Isolated, can_reach_outside));
+        assert!(!holds_in_sandbox(NetworkMode::Isolated, is_loopback_up));
+    }
+
+    #[test]
+    fn loopback_has_only_loopback() {
+        assert!(!holds_in_sandbox(NetworkMode::Loopback, can_reach_outside));
+        assert!(holds_in_sandbox(NetworkMode::Loopback, is_loopback_up));
+    }
+}
+
+mod inherited_fds {
+    use std::{
+        ffi::CString,
+        io::{self, Read},
+    };
+
+    use nix::{sys::wait, unistd::ForkResult};
+
+    use super::*;
+
+    #[test]
+    fn only_standard_streams_reach_exec() {
+        assert_only_standard_streams_reach_exec(close_inherited_fds);
+    }
+
+    #[test]
+    fn only_standard_streams_reach_exec_on_older_kernels() {
+        assert_only_standard_streams_reach_exec(set_cloexec_to_listed_fds);
+    }
+
+    fn assert_only_standard_streams_reach_exec(
+        close_inherited_fds: fn() -> Result<(), nix::Error>,
+    ) {
+        let (mut reader, writer) = io::pipe().expect("Failed to create pipe");
+        // Leak a descriptor without `O_CLOEXEC`, as a host process may.
+        let leaked = unsafe { libc::dup(reader.as_raw_fd()) };
+        assert!(leaked > 2, "Failed to duplicate descriptor");
+
+        match unsafe { unistd::fork() }.expect("Failed to fork process") {
+            ForkResult::Parent { child } => {
+                drop(writer);
+                let mut listed = String::new();
+                reader
+                    .read_to_string(&mut listed)
+                    .expect("Failed to read listed descriptors");
+                let _ = wait::waitpid(child, None);
+                unsafe { libc::close(leaked) };
+
+                let fds: Vec<&str> = listed.split_whitespace().collect();
+                assert_eq!(fds, ["0", "1", "2"]);
+            }
+            ForkResult::Child => {
+                let code = match unistd::dup2_stdout(&writer).and_then(|_| close_inherited_fds()) {
+                    Ok(_) => {
+                        // List the descriptors of the shell from a child of
+                        // it, as `ls` has one open for the listing itself.
+                        let args = ["sh", "-c", "ls /proc/$$/fd; :"]
+                            .map(|arg| CString::new(arg).expect("Invalid argument"));
+                        let _ = unistd::execv(c"/bin/sh", &args);
+                        127
+                    }
+                    Err(_) => 126,
+                };
+                unsafe { libc::_exit(code) }
+            }
+        }
+    }
 }
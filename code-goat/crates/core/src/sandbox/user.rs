@@ -0,0 +1,149 @@
+use std::fs;
+
+use log::info;
+use nix::{
+    errno::Errno,
+    libc,
+    unistd::{self, Gid, Pid, Uid},
+};
+use parking_lot::Mutex;
+
+use crate::models::InternalError;
+
+/// ID of `nobody` and `nogroup`, which a judger running as root maps the
+/// sandbox to unless configured otherwise.
+const OVERFLOW_ID: u32 = 65534;
+
+/// ID of the user and group the untrusted code runs as inside the sandbox.
+/// It must not be root of the user namespace, which keeps every capability
+/// in it across `execve` (e.g. to remount the read-only root).
+pub(crate) const SANDBOX_ID: u32 = 1000;
+
+/// User and group mapping of the sandbox, if configured.
+static ID_MAP: Mutex<Option<IdMap>> = Mutex::new(None);
+
+/// Maps a single host user and group to a user and group inside the
+/// sandbox. Processes of the sandbox act as the host user on every file
+/// outside of it, so the working directory must be writable by them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdMap {
+    pub host_uid: u32,
+    pub host_gid: u32,
+    pub sandbox_uid: u32,
+    pub sandbox_gid: u32,
+}
+
+impl Default for IdMap {
+    /// Map the user and group of the judger to [`SANDBOX_ID`] inside the
+    /// sandbox, unless the judger runs as root on the host.
+    fn default() -> Self {
+        let host_id = |id: u32| if id == 0 { OVERFLOW_ID } else { id };
+        Self {
+            host_uid: host_id(Uid::effective().as_raw()),
+            host_gid: host_id(Gid::effective().as_raw()),
+            sandbox_uid: SANDBOX_ID,
+            sandbox_gid: SANDBOX_ID,
+        }
+    }
+}
+
+/// Set the user and group to run the sandbox as. Mapping to any host user
+/// other than the judger itself requires the judger to run as root.
+pub fn configure_id_map(id_map: IdMap) -> Result<(), InternalError> {
+    // The sandbox must never hold the privileges of host root, nor those
+    // of root of its own namespace.
+    if id_map.host_uid == 0
+        || id_map.host_gid == 0
+        || id_map.sandbox_uid == 0
+        || id_map.sandbox_gid == 0
+    {
+        return Err(InternalError::IdMap(format!("{:?}", id_map)));
+    }
+
+    *ID_MAP.lock() = Some(id_map);
+    info!("Configured id map to {:?}.", id_map);
+    Ok(())
+}
+
+/// The configured mapping, or the default one.
+pub(crate) fn get_id_map() -> IdMap {
+    ID_MAP.lock().unwrap_or_default()
+}
+
+/// Write `uid_map` and `gid_map` of the user namespace of `pid`.
+pub(crate) fn write_id_map(pid: Pid, id_map: &IdMap) -> Result<(), InternalError> {
+    let proc_dir = format!("/proc/{}", pid);
+
+    // Only a judger with `CAP_SETGID` may let the runner call `setgroups`.
+    if !Uid::effective().is_root() {
+        fs::write(format!("{}/setgroups", proc_dir), "deny").map_err(InternalError::WriteIdMap)?;
+    }
+    fs::write(
+        format!("{}/uid_map", proc_dir),
+        format!("{} {} 1\n", id_map.sandbox_uid, id_map.host_uid),
+    )
+    .map_err(InternalError::WriteIdMap)?;
+    fs::write(
+        format!("{}/gid_map", proc_dir),
+        format!("{} {} 1\n", id_map.sandbox_gid, id_map.host_gid),
+    )
+    .map_err(InternalError::WriteIdMap)
+}
+
+/// Switch the runner to the mapped user and group, dropping supplementary
+/// groups inherited from the judger if it is allowed to.
+///
+/// The runner keeps its capabilities in the user namespace, as it is not
+/// switched away from root of it, so that it can still set up the sandbox.
+/// `execve` drops them for good, and `no_new_privs` keeps file capabilities
+/// of the host from granting any back.
+pub(crate) fn switch_user(id_map: &IdMap) -> Result<(), nix::Error> {
+    match unistd::setgroups(&[]) {
+        Ok(_) | Err(nix::Error::EPERM) => {}
+        Err(e) => return Err(e),
+    }
+
+    let gid = Gid::from_raw(id_map.sandbox_gid);
+    unistd::setresgid(gid, gid, gid)?;
+    let uid = Uid::from_raw(id_map.sandbox_uid);
+    unistd::setresuid(uid, uid, uid)?;
+
+    Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_never_maps_host_root() {
+        let id_map = IdMap::default();
+        assert_ne!(id_map.host_uid, 0);
+        assert_ne!(id_map.host_gid, 0);
+    }
+
+    #[test]
+    fn default_never_runs_as_sandbox_root() {
+        let id_map = IdMap::default();
+        assert_ne!(id_map.sandbox_uid, 0);
+        assert_ne!(id_map.sandbox_gid, 0);
+    }
+
+    #[test]
+    fn host_root_is_rejected() {
+        let id_map = IdMap {
+            host_uid: 0,
+            ..IdMap::default()
+        };
+        assert!(configure_id_map(id_map).is_err());
+    }
+
+    #[test]
+    fn sandbox_root_is_rejected() {
+        let id_map = IdMap {
+            sandbox_uid: 0,
+            ..IdMap::default()
+        };
+        assert!(configure_id_map(id_map).is_err());
+    }
+}
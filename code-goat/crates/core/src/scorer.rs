@@ -0,0 +1,171 @@
+use log::info;
+
+use crate::{
+    judger,
+    models::{Aggregation, JudgeSpec, ProblemReport, Subtask, SubtaskReport},
+};
+
+/// The entry point for judging a submission against a whole problem.
+///
+/// Like [`crate::judge_batch`], only `input_path` and `answer_path` of
+/// `spec` are replaced by those of each testcase. Remaining testcases of
+/// a subtask are skipped once its score cannot change anymore, which never
+/// happens early for [`Aggregation::Sum`].
+pub fn judge_problem(mut spec: JudgeSpec, subtasks: Vec<Subtask>) -> ProblemReport {
+    let mut reports = Vec::with_capacity(subtasks.len());
+
+    for (index, subtask) in subtasks.into_iter().enumerate() {
+        let n_testcases = subtask.testcases.len();
+        let mut results = Vec::with_capacity(n_testcases);
+        let mut scores = Vec::with_capacity(n_testcases);
+
+        for testcase in subtask.testcases {
+            spec.input_path = testcase.input_path;
+            spec.answer_path = testcase.answer_path;
+
+            let result = judger::judge_once(&spec);
+            scores.push(result.get_score());
+            results.push(result);
+
+            if is_decided(subtask.aggregation, &scores) {
+                break;
+            }
+        }
+
+        let skipped = n_testcases - results.len();
+        if skipped > 0 {
+            info!("Skipped {} testcases of subtask #{}.", skipped, index + 1);
+        }
+
+        reports.push(SubtaskReport {
+            score: subtask.points * aggregate(subtask.aggregation, &scores, n_testcases),
+            points: subtask.points,
+            results,
+            skipped,
+        });
+    }
+
+    ProblemReport {
+        score: reports.iter().map(|report| report.score).sum(),
+        points: reports.iter().map(|report| report.points).sum(),
+        subtasks: reports,
+        message: None,
+    }
+}
+
+/// Whether the score of a subtask is decided by the scores so far.
+fn is_decided(aggregation: Aggregation, scores: &[f64]) -> bool {
+    match aggregation {
+        Aggregation::AllOrNothing => scores.iter().any(|&score| score < 1.0),
+        Aggregation::Min => scores.iter().any(|&score| score <= 0.0),
+        // Every remaining testcase can still add its share of the points,
+        // even after a failure, so skipping it would take points away.
+        Aggregation::Sum => false,
+    }
+}
+
+/// Fraction of the points of a subtask earned with `scores`. Testcases
+/// that were skipped count as failed.
+fn aggregate(aggregation: Aggregation, scores: &[f64], n_testcases: usize) -> f64 {
+    if n_testcases == 0 {
+        return 0.0;
+    }
+    let is_complete = scores.len() == n_testcases;
+
+    match aggregation {
+        Aggregation::AllOrNothing => {
+            if is_complete && scores.iter().all(|&score| score >= 1.0) {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        Aggregation::Min if is_complete => scores.iter().copied().fold(1.0, f64::min),
+        Aggregation::Min => 0.0,
+        Aggregation::Sum => scores.iter().sum::<f64>() / n_testcases as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::{
+        models::{ResourceLimit, Testcase},
+        sandbox::seccomp::ScmpPolicy,
+    };
+
+    #[test]
+    fn all_or_nothing_needs_every_testcase() {
+        assert!(!is_decided(Aggregation::AllOrNothing, &[1.0, 1.0]));
+        assert!(is_decided(Aggregation::AllOrNothing, &[1.0, 0.5]));
+
+        assert_eq!(aggregate(Aggregation::AllOrNothing, &[1.0, 1.0], 2), 1.0);
+        assert_eq!(aggregate(Aggregation::AllOrNothing, &[1.0, 0.5], 3), 0.0);
+    }
+
+    #[test]
+    fn min_takes_lowest_score() {
+        assert!(!is_decided(Aggregation::Min, &[1.0, 0.5]));
+        assert!(is_decided(Aggregation::Min, &[1.0, 0.0]));
+
+        assert_eq!(aggregate(Aggregation::Min, &[1.0, 0.5, 0.75], 3), 0.5);
+        assert_eq!(aggregate(Aggregation::Min, &[1.0, 0.0], 3), 0.0);
+    }
+
+    #[test]
+    fn sum_splits_points() {
+        assert!(!is_decided(Aggregation::Sum, &[0.0, 0.0]));
+        assert!(!is_decided(Aggregation::Sum, &[1.0, 0.0, 0.5]));
+
+        assert_eq!(aggregate(Aggregation::Sum, &[1.0, 0.5, 0.0, 1.0], 4), 0.625);
+    }
+
+    #[test]
+    fn sum_runs_every_testcase_after_failure() {
+        let workspace = env::temp_dir().join(format!("code-goat-sum-{}", process::id()));
+        fs::create_dir_all(&workspace).expect("Failed to create workspace");
+        let testcases = ["1", "0", "0", "1"]
+            .iter()
+            .enumerate()
+            .map(|(i, is_passed)| {
+                let path = workspace.join(i.to_string());
+                fs::write(&path, is_passed).expect("Failed to write input");
+                Testcase {
+                    input_path: path.to_str().map(String::from),
+                    answer_path: None,
+                }
+            })
+            .collect();
+        let spec = JudgeSpec::try_new(
+            "/bin/sh",
+            None,
+            None,
+            None,
+            None,
+            vec!["/bin/sh", "-c", "read is_passed; exit $((1 - is_passed))"],
+            vec![],
+            ScmpPolicy::Unsafe,
+            ResourceLimit::new(None, None, Some(5000), None, None, None),
+        )
+        .expect("Invalid spec");
+        let subtask = Subtask {
+            points: 4.0,
+            aggregation: Aggregation::Sum,
+            testcases,
+        };
+
+        let report = judge_problem(spec, vec![subtask]);
+        let _ = fs::remove_dir_all(&workspace);
+
+        let subtask = &report.subtasks[0];
+        assert_eq!((subtask.results.len(), subtask.skipped), (4, 0));
+        assert_eq!(report.score, 2.0, "{:?}", subtask.results);
+    }
+
+    #[test]
+    fn empty_subtask_scores_nothing() {
+        assert_eq!(aggregate(Aggregation::Min, &[], 0), 0.0);
+    }
+}
@@ -13,7 +13,9 @@ import (
 	"time"
 	"unsafe"
 
+	"github.com/aintbe/code-goat/evaluator/constants"
 	"github.com/aintbe/code-goat/evaluator/profile"
+	"github.com/aintbe/code-goat/evaluator/types"
 	"github.com/aintbe/code-goat/evaluator/utils"
 )
 
@@ -42,31 +44,60 @@ func RunCodeGoat(spec *profile.JudgeSpec) (*JudgeResult, error) {
 		return cStr
 	}
 
+	// Bind the working directory if any, as the minimal root hides it.
+	var mounts []C.CMount
+	if spec.Workdir != "" {
+		mounts = append(mounts, C.CMount{
+			target:    allocate(spec.Workdir),
+			source:    allocate(spec.Workdir),
+			read_only: C.bool(spec.ReadOnlyWorkdir),
+		})
+	}
+	var cMounts *C.CMount
+	if len(mounts) > 0 {
+		cMounts = &mounts[0]
+	}
+
 	// Convert Go Spec into C Spec to pass to Rust FFI function.
 	cSpec := C.CJudgeSpec{
 		exe_path:   allocate(spec.ExePath),
 		input_path: allocate(spec.InputPath),
 		// Do not grade inside judger to run it under the same condition.
-		answer_path: allocate(""),
-		output_path: allocate(spec.OutputPath),
-		error_path:  allocate(spec.ErrorPath),
-		args:        allocate(spec.Args.String()),
-		envs:        allocate(spec.Envs.String()),
-		scmp_policy: C.uint8_t(spec.ScmpPolicy),
-		resource_limit: C.CResourceLimit{
-			memory:    C.uint64_t(spec.ResourceLimit.Memory),
-			cpu_time:  C.uint32_t(spec.ResourceLimit.CpuTime),
-			real_time: C.uint32_t(spec.ResourceLimit.RealTime),
-			stack:     C.uint32_t(spec.ResourceLimit.Stack),
-			n_process: C.uint16_t(spec.ResourceLimit.NProcess),
-			output:    C.uint32_t(spec.ResourceLimit.Output),
-		},
+		answer_path:    allocate(""),
+		output_path:    allocate(spec.OutputPath),
+		error_path:     allocate(spec.ErrorPath),
+		args:           allocate(spec.Args.String()),
+		envs:           allocate(spec.Envs.String()),
+		scmp_policy:    C.uint8_t(spec.ScmpPolicy),
+		resource_limit: toCResourceLimit(spec.ResourceLimit),
+		network:        allocate(spec.Network),
+		share_ipc:      C.bool(spec.ShareIpc),
+		share_cgroup:   C.bool(spec.ShareCgroup),
+		root:           allocate(spec.Root),
+		mounts:         cMounts,
+		n_mounts:       C.size_t(len(mounts)),
+		workdir:        allocate(spec.Workdir),
 	}
 
 	// Configure logger inside judger. Uncomment below line to log into a files.
 	// C.judger_configure_logger(allocate(spec.LogPath))
 	C.judger_configure_logger(nil)
 
+	// Run the submission as the users of the profile, if any. Otherwise
+	// code-goat picks an unprivileged user both on the host and inside
+	// its namespace.
+	if idMap := spec.IdMap; idMap.IsSet() {
+		status := C.judger_configure_id_map(
+			C.uint(idMap.HostUid),
+			C.uint(idMap.HostGid),
+			C.uint(idMap.SandboxUid),
+			C.uint(idMap.SandboxGid),
+		)
+		if status != 0 {
+			return nil, fmt.Errorf("invalid id map %+v", idMap)
+		}
+	}
+
 	// Call Rust FFI function to run judger.
 	res := C.judger_judge(cSpec)
 	elapsedTime := time.Since(startTime)
@@ -87,3 +118,87 @@ func RunCodeGoat(spec *profile.JudgeSpec) (*JudgeResult, error) {
 	}
 	return judgeResult, nil
 }
+
+// Grade the output against the answer with the built-in grader of code-goat,
+// so that every judger is graded under the same rule.
+func gradeOutput(outputPath string, answerPath string, mode profile.CompareMode) (bool, error) {
+	cOutputPath := C.CString(outputPath)
+	defer C.free(unsafe.Pointer(cOutputPath))
+	cAnswerPath := C.CString(answerPath)
+	defer C.free(unsafe.Pointer(cAnswerPath))
+
+	cMode := C.CCompareMode{
+		abs_eps: C.double(mode.AbsEps),
+		rel_eps: C.double(mode.RelEps),
+	}
+	if mode.Mode != "" {
+		cMode.mode = C.CString(mode.Mode)
+		defer C.free(unsafe.Pointer(cMode.mode))
+	}
+
+	res := C.judger_grade_output(cOutputPath, cAnswerPath, cMode)
+	if res < 0 {
+		return false, fmt.Errorf("failed to grade output %s", outputPath)
+	}
+	return res > 0, nil
+}
+
+func toCResourceLimit(limit profile.ResourceLimit) C.CResourceLimit {
+	return C.CResourceLimit{
+		memory:    C.uint64_t(limit.Memory),
+		cpu_time:  C.uint32_t(limit.CpuTime),
+		real_time: C.uint32_t(limit.RealTime),
+		stack:     C.uint32_t(limit.Stack),
+		n_process: C.uint16_t(limit.NProcess),
+		output:    C.uint32_t(limit.Output),
+		scratch:   C.uint64_t(limit.Scratch),
+	}
+}
+
+// languageRun is how code-goat runs an executable of a language.
+type languageRun struct {
+	ExePath       string                `json:"exe_path"`
+	Args          []string              `json:"args"`
+	ScmpPolicy    string                `json:"scmp_policy"`
+	ResourceLimit profile.ResourceLimit `json:"resource_limit"`
+}
+
+var scmpPolicies = map[string]constants.ScmpPolicy{
+	"unsafe":   constants.ScmpUnsafe,
+	"strict":   constants.ScmpStrict,
+	"python":   constants.ScmpPython,
+	"java":     constants.ScmpJava,
+	"threaded": constants.ScmpThreaded,
+}
+
+// AdjustToLanguage adjusts the config with the language profile of code-goat,
+// so that every judger runs the executable in targetDir the same way.
+func AdjustToLanguage(c *profile.Config, language constants.Language, targetDir string) error {
+	cLanguage := C.CString(string(language))
+	defer C.free(unsafe.Pointer(cLanguage))
+	cTargetDir := C.CString(targetDir)
+	defer C.free(unsafe.Pointer(cTargetDir))
+
+	res := C.judger_adjust_to_language(cLanguage, cTargetDir, toCResourceLimit(c.ResourceLimit))
+	if res == nil {
+		return fmt.Errorf("unsupported language: %s", language)
+	}
+	defer C.judger_free(res)
+
+	run, err := utils.Unmarshal[languageRun]([]byte(C.GoString(res)))
+	if err != nil {
+		return err
+	}
+	scmpPolicy, ok := scmpPolicies[run.ScmpPolicy]
+	if !ok {
+		return fmt.Errorf("unsupported seccomp policy: %s", run.ScmpPolicy)
+	}
+
+	c.ExePath = run.ExePath
+	c.Args = append(types.StringSlice(run.Args), c.Args...)
+	c.ScmpPolicy = scmpPolicy
+	fmt.Printf("+ Original limit: %+v\n", c.ResourceLimit)
+	c.ResourceLimit = run.ResourceLimit
+	fmt.Printf("+ Adjusted limit for %s: %+v\n", language, c.ResourceLimit)
+	return nil
+}
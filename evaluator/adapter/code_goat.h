@@ -1,3 +1,5 @@
+#include <stdbool.h>
+#include <stddef.h>
 #include <stdint.h>
 
 typedef struct {
@@ -7,8 +9,28 @@ typedef struct {
     uint32_t stack;
     uint16_t n_process;
     uint32_t output;
+    uint64_t scratch;
 } CResourceLimit;
 
+typedef struct {
+    const char *mode;
+    double abs_eps;
+    double rel_eps;
+} CCompareMode;
+
+typedef struct {
+    const char *exe_path;
+    const char *scmp_policy;
+    CResourceLimit resource_limit;
+} CHelperSpec;
+
+typedef struct {
+    const char *target;
+    const char *source;
+    bool read_only;
+    uint64_t tmpfs_size;
+} CMount;
+
 typedef struct {
     const char *exe_path;
     const char *input_path;
@@ -19,10 +41,61 @@ typedef struct {
     const char *envs;
     uint8_t scmp_policy;
     CResourceLimit resource_limit;
+    CCompareMode compare_mode;
+    CHelperSpec checker;
+    CHelperSpec interactor;
+    const char *network;
+    bool share_ipc;
+    bool share_cgroup;
+    const char *root;
+    const CMount *mounts;
+    size_t n_mounts;
+    const char *workdir;
 } CJudgeSpec;
 
+typedef struct {
+    const char *compiler_path;
+    const char *source_paths;
+    const char *exe_path;
+    const char *diagnostics_path;
+    const char *args;
+    const char *envs;
+    const char *scmp_policy;
+    CResourceLimit resource_limit;
+} CCompileSpec;
+
+typedef struct {
+    const char *input_path;
+    const char *answer_path;
+} CTestcase;
+
+typedef struct {
+    double points;
+    const char *aggregation;
+    const CTestcase *testcases;
+    size_t n_testcases;
+} CSubtask;
+
 char* judger_judge(CJudgeSpec spec);
 
+char* judger_judge_batch(CJudgeSpec spec, const CTestcase *testcases, size_t n_testcases, bool stop_on_failure);
+
+char* judger_judge_problem(CJudgeSpec spec, const CSubtask *subtasks, size_t n_subtasks);
+
+char* judger_compile(CCompileSpec spec);
+
+char* judger_compile_language(const char* language, const char* source_paths, const char* workdir, CResourceLimit resource_limit);
+
+char* judger_adjust_to_language(const char* language, const char* workdir, CResourceLimit resource_limit);
+
 void judger_free(char* return_value);
 
+int judger_grade_output(const char* output_path, const char* answer_path, CCompareMode compare_mode);
+
+int judger_configure_cgroup(const char* parent);
+
+int judger_configure_cpus(const char* cpus);
+
+int judger_configure_id_map(unsigned int host_uid, unsigned int host_gid, unsigned int sandbox_uid, unsigned int sandbox_gid);
+
 int judger_configure_logger(const char* log_path);
\ No newline at end of file
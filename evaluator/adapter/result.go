@@ -1,7 +1,6 @@
 package adapter
 
 import (
-	"bytes"
 	"time"
 
 	"github.com/aintbe/code-goat/evaluator/profile"
@@ -17,12 +16,16 @@ type JudgeResult struct {
 	Signal        *string        `json:"signal"`
 	ResourceUsage *ResourceUsage `json:"resource_usage"`
 	Output        *string        `json:"output"`
+	Score         *float64       `json:"score"`
+	Network       *string        `json:"network"`
 }
 
 type ResourceUsage struct {
-	Memory   uint64 `json:"memory"`
-	CpuTime  uint32 `json:"cpu_time"`
-	RealTime uint32 `json:"real_time"`
+	Memory   uint64  `json:"memory"`
+	CpuTime  uint32  `json:"cpu_time"`
+	RealTime uint32  `json:"real_time"`
+	NProcess *uint64 `json:"n_process"`
+	Scratch  *uint64 `json:"scratch"`
 }
 
 type JudgeStatus string
@@ -31,10 +34,18 @@ const (
 	Exited                JudgeStatus = "Exited"
 	Accepted              JudgeStatus = "Accepted"
 	WrongAnswer           JudgeStatus = "WrongAnswer"
+	PartiallyCorrect      JudgeStatus = "PartiallyCorrect"
+	PresentationError     JudgeStatus = "PresentationError"
 	CpuTimeLimitExceeded  JudgeStatus = "CpuTimeLimitExceeded"
 	RealTimeLimitExceeded JudgeStatus = "RealTimeLimitExceeded"
 	MemoryLimitExceeded   JudgeStatus = "MemoryLimitExceeded"
+	OutputLimitExceeded   JudgeStatus = "OutputLimitExceeded"
+	ProcessLimitExceeded  JudgeStatus = "ProcessLimitExceeded"
+	ScratchLimitExceeded  JudgeStatus = "ScratchLimitExceeded"
 	RuntimeError          JudgeStatus = "RuntimeError"
+	SecurityViolation     JudgeStatus = "SecurityViolation"
+	CompileError          JudgeStatus = "CompileError"
+	CheckerError          JudgeStatus = "CheckerError"
 	InternalError         JudgeStatus = "InternalError"
 )
 
@@ -43,15 +54,18 @@ func (j *JudgeResult) Grade(spec *profile.JudgeSpec) error {
 		return nil
 	}
 
-	output, err := utils.ReadBytes(spec.OutputPath)
+	accepted, err := gradeOutput(spec.OutputPath, spec.AnswerPath, spec.CompareMode)
 	if err != nil {
 		return err
 	}
 
-	accepted := bytes.Equal(spec.ExpectedOutput, output)
 	if accepted {
 		j.Status = Accepted
 	} else {
+		output, err := utils.ReadBytes(spec.OutputPath)
+		if err != nil {
+			return err
+		}
 		j.Status = WrongAnswer
 		outputStr := string(output)
 		j.Output = &outputStr
@@ -15,4 +15,7 @@ const (
 	ScmpUnsafe ScmpPolicy = 0 + iota
 	ScmpStrict
 	ScmpPython
+	ScmpCompile
+	ScmpJava
+	ScmpThreaded
 )
@@ -20,6 +20,11 @@ func main() {
 	if err != nil {
 		log.Fatalln(err)
 	}
+	// Run the submission of every judger the way code-goat does.
+	err = adapter.AdjustToLanguage(config, benchmark.Language, benchmark.GetTargetDir())
+	if err != nil {
+		log.Fatalln(err)
+	}
 	testcases, err := profile.LoadTestCases(benchmark)
 	if err != nil {
 		log.Fatalln(err)
@@ -11,20 +11,56 @@ import (
 )
 
 type Config struct {
-	ExePath       string
-	ScmpPolicy    constants.ScmpPolicy
-	Args          types.StringSlice `yaml:"args"`
-	Envs          types.StringSlice `yaml:"envs"`
-	ResourceLimit ResourceLimit     `yaml:"limit"`
+	ExePath         string
+	ScmpPolicy      constants.ScmpPolicy
+	Args            types.StringSlice `yaml:"args"`
+	Envs            types.StringSlice `yaml:"envs"`
+	ResourceLimit   ResourceLimit     `yaml:"limit"`
+	CompareMode     CompareMode       `yaml:"compare"`
+	// Network is "isolated" (default if empty) or "loopback".
+	Network         string            `yaml:"network"`
+	ShareIpc        bool              `yaml:"share_ipc"`
+	ShareCgroup     bool              `yaml:"share_cgroup"`
+	// Root is "masked" (default if empty) or "minimal".
+	Root            string            `yaml:"root"`
+	// ReadOnlyWorkdir binds the working directory read-only, which is
+	// writable by default.
+	ReadOnlyWorkdir bool              `yaml:"read_only_workdir"`
+	IdMap           IdMap             `yaml:"id_map"`
+}
+
+// IdMap is the host user and group the sandbox of code-goat runs as, and
+// the user and group they appear as inside it. Unset (zero) ids are left
+// to code-goat, which maps the judger's own user on the host, or nobody
+// if it runs as root, to 1000 inside the sandbox.
+type IdMap struct {
+	HostUid    uint32 `yaml:"host_uid"`
+	HostGid    uint32 `yaml:"host_gid"`
+	SandboxUid uint32 `yaml:"sandbox_uid"`
+	SandboxGid uint32 `yaml:"sandbox_gid"`
+}
+
+// IsSet reports whether the profile sets any id of the map.
+func (m IdMap) IsSet() bool {
+	return m != IdMap{}
+}
+
+// CompareMode decides how code-goat grades the output against the answer.
+// Leave Mode empty to compare line by line ignoring trailing whitespace.
+type CompareMode struct {
+	Mode   string  `yaml:"mode"`
+	AbsEps float64 `yaml:"abs_eps"`
+	RelEps float64 `yaml:"rel_eps"`
 }
 
 type ResourceLimit struct {
-	Memory   uint64 `yaml:"memory"`
-	CpuTime  uint32 `yaml:"cpu_time"`
-	RealTime uint32 `yaml:"real_time"`
-	Stack    uint32 `yaml:"stack"`
-	NProcess uint16 `yaml:"n_process"`
-	Output   uint32 `yaml:"output"`
+	Memory   uint64 `yaml:"memory" json:"memory"`
+	CpuTime  uint32 `yaml:"cpu_time" json:"cpu_time"`
+	RealTime uint32 `yaml:"real_time" json:"real_time"`
+	Stack    uint32 `yaml:"stack" json:"stack"`
+	NProcess uint16 `yaml:"n_process" json:"n_process"`
+	Output   uint32 `yaml:"output" json:"output"`
+	Scratch  uint64 `yaml:"scratch" json:"scratch"`
 }
 
 func LoadConfig(b *Benchmark) (*Config, error) {
@@ -41,31 +77,5 @@ func LoadConfig(b *Benchmark) (*Config, error) {
 		return nil, utils.Error(err, "unmarshal", string(yamlBytes))
 	}
 
-	// Adjust the config according to the configured language.
-	err = config.adjustToLanguage(b.Language, b.GetTargetDir())
-
-	return &config, err
-}
-
-func (c *Config) adjustToLanguage(language constants.Language, targetDir string) error {
-	switch language {
-	case constants.C, constants.Cpp:
-		c.ExePath = targetDir + "main"
-		c.ScmpPolicy = constants.ScmpStrict
-
-	case constants.Python:
-		c.ExePath = "/usr/bin/python3"
-		c.Args = append(types.StringSlice{targetDir + "__pycache__/main.cpython-312.pyc"}, c.Args...)
-		c.ScmpPolicy = constants.ScmpPython
-
-		fmt.Printf("+ Original memory limit: %d bytes\n", c.ResourceLimit.Memory)
-		c.ResourceLimit.Memory = (2*c.ResourceLimit.Memory + 32*constants.MiB)
-		fmt.Printf("+ Adjusted memory limit for Python: %d bytes\n", c.ResourceLimit.Memory)
-		c.ResourceLimit.CpuTime = 3*c.ResourceLimit.CpuTime + 2
-		c.ResourceLimit.RealTime = 3*c.ResourceLimit.RealTime + 2
-
-	default:
-		return fmt.Errorf("unsupported language: %s", language)
-	}
-	return nil
+	return &config, nil
 }
@@ -7,12 +7,13 @@ import (
 )
 
 type JudgeSpec struct {
-	InputPath      string
-	ExpectedOutput []byte
-	OutputPath     string
-	ErrorPath      string
-	LogPath        string
-	Config         // Embed
+	InputPath  string
+	AnswerPath string
+	OutputPath string
+	ErrorPath  string
+	LogPath    string
+	Workdir    string
+	Config     // Embed
 }
 
 func NewJudgeSpec(b *Benchmark, t *TestCase, c *Config) (*JudgeSpec, error) {
@@ -21,18 +22,19 @@ func NewJudgeSpec(b *Benchmark, t *TestCase, c *Config) (*JudgeSpec, error) {
 	if t.HasInput {
 		inputPath = testcase + ".in"
 	}
-	expectedOutput, err := utils.ReadBytes(testcase + ".out")
-	if err != nil {
+	answerPath := testcase + ".out"
+	if _, err := utils.ReadBytes(answerPath); err != nil {
 		return nil, err
 	}
 
 	targetDir := b.GetTargetDir()
 	return &JudgeSpec{
-		InputPath:      inputPath,
-		ExpectedOutput: expectedOutput,
-		OutputPath:     targetDir + t.Id + ".out",
-		ErrorPath:      targetDir + t.Id + ".err",
-		LogPath:        targetDir + t.Id + ".log",
-		Config:         *c,
+		InputPath:  inputPath,
+		AnswerPath: answerPath,
+		OutputPath: targetDir + t.Id + ".out",
+		ErrorPath:  targetDir + t.Id + ".err",
+		LogPath:    targetDir + t.Id + ".log",
+		Workdir:    targetDir,
+		Config:     *c,
 	}, nil
 }
@@ -53,7 +53,14 @@ if [ $BUILD_FLAG -eq 1 ]; then
 fi
 
 # 4. Perform compilation if requested or the executable file does not exists
-if [ "$COMPILE_FLAG" -eq 1 ] || [ ! -f "$TEST_PATH/main" ]; then
+EXE_PATH="$TEST_PATH/main"
+if [ "$LANGUAGE" = "java" ]; then
+    EXE_PATH="$TEST_PATH/Main.class"
+elif [ "$LANGUAGE" = "python3" ]; then
+    EXE_PATH="$TEST_PATH/main.pyc"
+fi
+
+if [ "$COMPILE_FLAG" -eq 1 ] || [ ! -f "$EXE_PATH" ]; then
 
     case "$LANGUAGE" in
         "c" )
@@ -65,10 +72,14 @@ if [ "$COMPILE_FLAG" -eq 1 ] || [ ! -f "$TEST_PATH/main" ]; then
         # "go")
         #     go build -o "$TEST_PATH/main" "$TEST_PATH/main"
         #     ;;
-        # "java")
-        #     # Also need to change how to run it 
-        #     javac -d "$TEST_PATH" "$TEST_PATH/Main.java"
-        #     ;;  
+        "java" )
+            javac -d "$TEST_PATH" "$TEST_PATH/Main.java"
+            ;;
+        # Same as the compile command of `python3` in languages.toml.
+        "python3" )
+            python3 -S -c "import py_compile, sys; py_compile.compile(sys.argv[1], sys.argv[2], doraise=True)" \
+                "$TEST_PATH/main.py" "$TEST_PATH/main.pyc"
+            ;;
         * )
             echo "Error: Unsupported language '$LANGUAGE'"
             exit 1 # Terminate